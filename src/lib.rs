@@ -1,24 +1,390 @@
 #![feature(array_chunks, once_cell, portable_simd)]
+#![cfg_attr(test, feature(test))]
 
 pub mod nodes;
-use arrayvec::ArrayVec;
+mod modulation;
+mod params;
+mod tuning;
+mod voices;
+use modulation::ModulationState;
 use nodes::*;
+use params::SeenthPluginParams;
+use std::{array, simd::f32x2};
+use tuning::mts::MtsMessage;
+use arrayvec::ArrayVec;
+use voices::{Allocation, PlayMode, VoiceExpression, VoiceHandler, VoiceKey, VoiceStealMode};
+
+/// MIDI channel whose messages affect every note of the (lower) MPE zone
+const MPE_MASTER_CHANNEL: u8 = 0;
+/// MPE "third dimension" of control
+const BRIGHTNESS_CC: u8 = 74;
+const SUSTAIN_CC: u8 = 64;
+const SOSTENUTO_CC: u8 = 66;
 
 pub struct SeenthPlugin<T: SeenthStandAlonePlugin, const VOICES: usize = MAX_POLYPHONY> {
-    voice_handler: ArrayVec<u8, VOICES>,
-    params: Arc<T>,
+    voice_handler: VoiceHandler<VOICES>,
+    params: Arc<SeenthPluginParams<T>>,
     processor: T::Processor,
+    /// pitch wheel position of every channel, from -1 to 1, all
+    /// the same, those of the last channel bent, without MPE
+    pitch_bend: [f32; 16],
+    /// last pressure and brightness received on every channel, new voices start with these
+    channel_expression: [VoiceExpression; 16],
+    modulation: ModulationState,
+    /// notes held down in the mono play modes, in the order they were pressed
+    note_stack: ArrayVec<VoiceKey, 128>,
+    /// normalized frequency of the last note played in the mono play modes, gliding starts from here
+    mono_freq: Option<f32>,
+    /// pitch, in (fractional) MIDI notes, of every note retuned with MTS messages, these
+    /// take precedence over the loaded tuning, until it changes, or the plugin is reset
+    mts_pitches: [Option<f32>; 128],
+    /// `tuning_version` of the plugin's parameters when `mts_pitches` were last cleared
+    tuning_version: u32,
 }
 
 impl<T: SeenthStandAlonePlugin, const N: usize> Default for SeenthPlugin<T, N> {
     fn default() -> Self {
 
-        let params: Arc<T> = Default::default();
+        let params: Arc<SeenthPluginParams<T>> = Default::default();
 
         Self {
             voice_handler: Default::default(),
             params: params.clone(),
-            processor: params.processor()
+            processor: params.node.clone().processor(),
+            pitch_bend: [0.; 16],
+            channel_expression: Default::default(),
+            modulation: Default::default(),
+            note_stack: Default::default(),
+            mono_freq: None,
+            mts_pitches: [None; 128],
+            tuning_version: params.tuning_version(),
+        }
+    }
+}
+
+impl<T: SeenthStandAlonePlugin, const VOICES: usize> SeenthPlugin<T, VOICES> {
+    fn update_modulation(&mut self) {
+        // the editor might be editing the routes, in which case, we'll try again later
+        if let (Ok(routes), Some(bindings)) = (
+            self.params.mod_routes.try_borrow(),
+            self.params.midi_learn.bindings(),
+        ) {
+            self.modulation.apply(
+                &routes,
+                &bindings,
+                self.params.node.modulation_targets(),
+                &mut self.processor,
+                &self.voice_handler,
+            );
+        }
+    }
+
+    /// Whether a message received on `channel` affects the voice at `voice_idx`
+    fn affects(&self, channel: u8, voice_idx: usize) -> bool {
+        (self.params.mpe.value() && channel == MPE_MASTER_CHANNEL)
+            || self.voice_handler.channel(voice_idx) == channel
+    }
+
+    /// Total pitch offset, in semitones, of the voice at `voice_idx`
+    fn voice_tuning(&self, voice_idx: usize) -> f32 {
+        let channel = self.voice_handler.channel(voice_idx);
+        let bend = self.pitch_bend[channel as usize];
+
+        let bend = if self.params.mpe.value() && channel != MPE_MASTER_CHANNEL {
+            let master_bend = self.pitch_bend[MPE_MASTER_CHANNEL as usize];
+            bend * self.params.mpe_bend_range.value() as f32
+                + self.params.bend_semitones(master_bend)
+        } else {
+            self.params.bend_semitones(bend)
+        };
+
+        bend + self.voice_handler.expression(voice_idx).tuning
+    }
+
+    fn update_tuning(&mut self, voice_idx: usize) {
+        let tuning = self.voice_tuning(voice_idx);
+        self.processor.set_voice_tuning(voice_idx, tuning);
+    }
+
+    /// Frequency of `note` in the loaded tuning, divided by the sample
+    /// rate, `None` if the tuning doesn't map the note to anything
+    fn norm_freq(&self, note: u8, context: &mut impl ProcessContext<Self>) -> Option<f32> {
+        let freq = match self.mts_pitches[note as usize] {
+            Some(pitch) => nih_plug::util::f32_midi_note_to_freq(pitch),
+            None => self.params.note_freq(note)?,
+        };
+
+        Some(freq / context.transport().sample_rate)
+    }
+
+    /// Make every voice playing `note` play it at its current pitch right away
+    fn retune_note(&mut self, note: u8, context: &mut impl ProcessContext<Self>) {
+        let Some(norm_freq) = self.norm_freq(note, context) else {
+            return;
+        };
+
+        for voice_idx in 0..self.voice_handler.len() {
+            if self.voice_handler.note(voice_idx) == note {
+                self.processor.glide_voice(voice_idx, norm_freq, 0.);
+            }
+        }
+    }
+
+    /// Allocate, and start a voice playing `key` at the given normalized
+    /// frequency, returning its index, or `None` if it had to be dropped
+    fn start_voice(
+        &mut self,
+        key: VoiceKey,
+        norm_freq: f32,
+        timing: u32,
+        context: &mut impl ProcessContext<Self>,
+    ) -> Option<usize> {
+        let steal_mode = self.params.steal_mode.value();
+
+        // the same note, held by a pedal, is being played again, let the old one go
+        if let Some(voice_idx) = self.voice_handler.release_sustained(key) {
+            self.processor.release_voice(voice_idx);
+        }
+
+        let Some(allocation) = self.voice_handler.note_on(key, steal_mode) else {
+            // no logging here, it would allocate, and lock
+            self.params.note_dropped();
+            return None;
+        };
+
+        if let Allocation::Stolen(voice_idx, stolen) = allocation {
+            self.processor.remove_voice(voice_idx);
+            context.send_event(stolen.terminated(timing));
+        }
+
+        self.processor.add_voice(norm_freq);
+
+        let voice_idx = self.voice_handler.len() - 1;
+        *self.voice_handler.expression_mut(voice_idx) =
+            self.channel_expression[key.channel as usize];
+
+        self.update_tuning(voice_idx);
+        self.update_modulation();
+
+        Some(voice_idx)
+    }
+
+    /// Make the mono voice play `key`, retriggering it, or not, depending on the play mode
+    fn play_mono(&mut self, key: VoiceKey, timing: u32, context: &mut impl ProcessContext<Self>) {
+        let Some(norm_freq) = self.norm_freq(key.note, context) else {
+            return;
+        };
+        let glide_time = self.params.glide.value() * 0.001;
+        let legato = self.params.play_mode.value() == PlayMode::Legato;
+
+        match self.voice_handler.held() {
+            Some(voice_idx) if legato => {
+                let previous = self.voice_handler.rekey(voice_idx, key);
+                if previous != key {
+                    context.send_event(previous.terminated(timing));
+                }
+                self.processor.glide_voice(voice_idx, norm_freq, glide_time);
+            }
+            _ => {
+                self.release_held_voice();
+
+                let start_freq = self.mono_freq.unwrap_or(norm_freq);
+                if let Some(voice_idx) = self.start_voice(key, start_freq, timing, context) {
+                    self.processor.glide_voice(voice_idx, norm_freq, glide_time);
+                }
+            }
+        }
+
+        self.mono_freq = Some(norm_freq);
+    }
+
+    fn release_held_voice(&mut self) {
+        if let Some(voice_idx) = self.voice_handler.held() {
+            self.voice_handler.release(voice_idx);
+            self.processor.release_voice(voice_idx);
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        event: NoteEvent<<Self as Plugin>::SysExMessage>,
+        context: &mut impl ProcessContext<Self>,
+    ) {
+        let timing = event.timing();
+
+        match event {
+
+            NoteEvent::NoteOn { note, channel, voice_id, .. } => {
+
+                let key = VoiceKey::new(channel, note, voice_id);
+
+                if self.params.play_mode.value() == PlayMode::Poly {
+                    if let Some(norm_freq) = self.norm_freq(note, context) {
+                        self.start_voice(key, norm_freq, timing, context);
+                    }
+                } else {
+                    self.note_stack.retain(|held| (held.note, held.channel) != (note, channel));
+                    if self.note_stack.is_full() {
+                        self.note_stack.remove(0);
+                    }
+                    self.note_stack.push(key);
+
+                    self.play_mono(key, timing, context);
+                }
+            }
+
+            NoteEvent::NoteOff { note, channel, voice_id, .. } => {
+
+                let stack_idx = self
+                    .note_stack
+                    .iter()
+                    .position(|held| (held.note, held.channel) == (note, channel));
+
+                if let Some(stack_idx) = stack_idx {
+                    self.note_stack.remove(stack_idx);
+
+                    // last note priority, go back to the previous note, if any
+                    if stack_idx == self.note_stack.len() {
+                        if let Some(&previous) = self.note_stack.last() {
+                            self.play_mono(previous, timing, context);
+                            return;
+                        }
+                    } else {
+                        // not the note currently playing
+                        return;
+                    }
+                }
+
+                if let Some(voice_idx) = self.voice_handler.note_off(voice_id, channel, note) {
+                    self.processor.release_voice(voice_idx);
+                }
+            }
+
+            NoteEvent::Choke { note, channel, voice_id, .. } => {
+
+                if let Some(voice_idx) = self.voice_handler.find(voice_id, channel, note) {
+                    let key = self.voice_handler.remove(voice_idx);
+                    self.processor.remove_voice(voice_idx);
+                    context.send_event(key.terminated(timing));
+                }
+            }
+
+            NoteEvent::PolyModulation { voice_id, poly_modulation_id, normalized_offset, .. } => {
+
+                if let Some(voice_idx) = self.voice_handler.find_id(voice_id) {
+                    self.processor.set_poly_modulation(
+                        voice_idx,
+                        poly_modulation_id,
+                        normalized_offset,
+                    );
+                }
+            }
+
+            // The parameter's new value is already set, and voices read it from the snapshot
+            // taken at the start of every block. Blocks are split at every event, so it
+            // takes effect at the next one, which starts right here.
+            NoteEvent::MonoAutomation { .. } => (),
+
+            NoteEvent::MidiPitchBend { channel, value, .. } => {
+
+                let bend = value * 2. - 1.;
+
+                if self.params.mpe.value() {
+                    self.pitch_bend[channel as usize] = bend;
+                } else {
+                    // without MPE, the pitch wheel bends every note, whatever its channel
+                    self.pitch_bend.fill(bend);
+                }
+
+                for voice_idx in 0..self.voice_handler.len() {
+                    self.update_tuning(voice_idx);
+                }
+            }
+
+            NoteEvent::PolyTuning { voice_id, channel, note, tuning, .. } => {
+
+                if let Some(voice_idx) = self.voice_handler.find(voice_id, channel, note) {
+                    self.voice_handler.expression_mut(voice_idx).tuning = tuning;
+                    self.update_tuning(voice_idx);
+                }
+            }
+
+            NoteEvent::PolyPressure { voice_id, channel, note, pressure, .. } => {
+
+                if let Some(voice_idx) = self.voice_handler.find(voice_id, channel, note) {
+                    self.voice_handler.expression_mut(voice_idx).pressure = pressure;
+                    self.update_modulation();
+                }
+            }
+
+            NoteEvent::PolyBrightness { voice_id, channel, note, brightness, .. } => {
+
+                if let Some(voice_idx) = self.voice_handler.find(voice_id, channel, note) {
+                    self.voice_handler.expression_mut(voice_idx).brightness = brightness;
+                    self.update_modulation();
+                }
+            }
+
+            NoteEvent::MidiChannelPressure { channel, pressure, .. } => {
+
+                self.channel_expression[channel as usize].pressure = pressure;
+
+                for voice_idx in 0..self.voice_handler.len() {
+                    if self.affects(channel, voice_idx) {
+                        self.voice_handler.expression_mut(voice_idx).pressure = pressure;
+                    }
+                }
+
+                self.update_modulation();
+            }
+
+            NoteEvent::MidiCC { cc: SUSTAIN_CC, value, .. } => {
+
+                for voice_idx in self.voice_handler.set_sustain_pedal(value >= 0.5) {
+                    self.processor.release_voice(voice_idx);
+                }
+            }
+
+            NoteEvent::MidiCC { cc: SOSTENUTO_CC, value, .. } => {
+
+                for voice_idx in self.voice_handler.set_sostenuto_pedal(value >= 0.5) {
+                    self.processor.release_voice(voice_idx);
+                }
+            }
+
+            NoteEvent::MidiCC { channel, cc, value, .. }
+                if cc == BRIGHTNESS_CC
+                    && self.params.mpe.value()
+                    && channel != MPE_MASTER_CHANNEL =>
+            {
+                self.channel_expression[channel as usize].brightness = value;
+
+                for voice_idx in 0..self.voice_handler.len() {
+                    if self.voice_handler.channel(voice_idx) == channel {
+                        self.voice_handler.expression_mut(voice_idx).brightness = value;
+                    }
+                }
+
+                self.update_modulation();
+            }
+
+            NoteEvent::MidiCC { cc, value, .. } => {
+
+                self.params.midi_learn.cc_received(cc, value);
+                self.modulation.set_cc(cc, value);
+                self.update_modulation();
+            }
+
+            NoteEvent::MidiSysEx { message, .. } => {
+
+                for (note, pitch) in message.changes() {
+                    if let Some(mts_pitch) = self.mts_pitches.get_mut(note as usize) {
+                        *mts_pitch = Some(pitch);
+                        self.retune_note(note, context);
+                    }
+                }
+            }
+            _ => (),
         }
     }
 }
@@ -48,17 +414,29 @@ impl<T: SeenthStandAlonePlugin, const VOICES: usize> Plugin for SeenthPlugin<T,
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
-    type SysExMessage = ();
-    type BackgroundTask = ();
+    type SysExMessage = MtsMessage;
+    type BackgroundTask = nodes::BackgroundTask;
 
     fn params(&self) -> Arc<dyn Params> { self.params.clone() }
 
-    fn editor(&self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+    fn task_executor(&self) -> TaskExecutor<Self> {
+        Box::new(nodes::BackgroundTask::run)
+    }
+
+    fn editor(&self, async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         let params = self.params.clone();
-        create_egui_editor(params.editor_state(), (), |_, _| (), move |ctx, setter, _| {
-            CentralPanel::default().show(ctx, |ui| {
+        let executor =
+            BackgroundExecutor::new(move |task| async_executor.execute_background(task));
+
+        create_egui_editor(params.node.editor_state(), (), |_, _| (), move |ctx, setter, _| {
+            params.midi_learn.poll(setter, params.node.as_ref());
+
+            TopBottomPanel::top("plugin").show(ctx, |ui| {
                 params.ui(ui, setter);
             });
+            CentralPanel::default().show(ctx, |ui| {
+                params.node.ui(ui, setter, &params.midi_learn, &executor);
+            });
         })
     }
 
@@ -69,6 +447,9 @@ impl<T: SeenthStandAlonePlugin, const VOICES: usize> Plugin for SeenthPlugin<T,
         context: &mut impl InitContext<Self>,
     ) -> bool {
 
+        // the tuning might have just been restored
+        self.params.tuning_changed();
+
         let (success, latency) = self.processor.initialize(buffer_config.sample_rate);
         context.set_latency_samples(latency);
         success
@@ -76,6 +457,13 @@ impl<T: SeenthStandAlonePlugin, const VOICES: usize> Plugin for SeenthPlugin<T,
 
     fn reset(&mut self) {
 
+        self.voice_handler.clear();
+        self.pitch_bend = [0.; 16];
+        self.channel_expression = Default::default();
+        self.note_stack.clear();
+        self.mono_freq = None;
+        self.mts_pitches = [None; 128];
+        self.modulation.reset();
         self.processor.reset();
     }
 
@@ -85,49 +473,110 @@ impl<T: SeenthStandAlonePlugin, const VOICES: usize> Plugin for SeenthPlugin<T,
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
+        // pick up changes made to the modulation routes
+        self.update_modulation();
+
+        // a newly loaded tuning replaces the MTS one, notes already playing keep their pitch
+        let tuning_version = self.params.tuning_version();
+        if tuning_version != self.tuning_version {
+            self.tuning_version = tuning_version;
+            self.mts_pitches = [None; 128];
+        }
+
+        // and to the bend ranges, voices glide to their new pitch, if it changed
+        for voice_idx in 0..self.voice_handler.len() {
+            self.update_tuning(voice_idx);
+        }
+
+        let num_samples = buffer.samples();
+        let channels = buffer.as_slice();
+
         let mut next_event = context.next_event();
+        let mut block_start = 0;
 
-        for (i, mut input_frame) in buffer.iter_samples().enumerate() {
+        while block_start < num_samples {
             while let Some(event) = next_event {
 
-                if event.timing() > i as u32 { break; }
+                if event.timing() > block_start as u32 { break; }
 
-                match event {
+                self.handle_event(event, context);
+                next_event = context.next_event();
+            }
 
-                    NoteEvent::NoteOn { note, .. } => {
+            // split blocks at events, for them to stay sample-accurate
+            let block_end = next_event
+                .map_or(num_samples, |event| event.timing() as usize)
+                .min(num_samples)
+                .min(block_start + MAX_BLOCK_SIZE);
 
-                        if let Ok(()) = self.voice_handler.try_push(note) {
-                            self.processor.add_voice(
-                                nih_plug::util::midi_note_to_freq(
-                                    note
-                                ) / context.transport().sample_rate,
-                            )
-                        };
-                    }
+            let block_len = block_end - block_start;
 
-                    NoteEvent::NoteOff { note, .. } => {
+            let mut inputs = [VoiceVector::splat(0.); MAX_BLOCK_SIZE];
+            let mut output = [VoiceVector::splat(0.); MAX_BLOCK_SIZE];
+            let mut voices_output = [VoiceVector::splat(0.); MAX_BLOCK_SIZE];
 
-                        for (i, &id) in self.voice_handler.iter().enumerate() {
-                            if note == id {
-                                self.voice_handler.swap_remove(i);
-                                self.processor.remove_voice(i);
-                                break;
-                            }
-                        }
-                    }
-                    _ => (),
+            let inputs = &mut inputs[..block_len];
+            let output = &mut output[..block_len];
+            let voices_output = &mut voices_output[..block_len];
+
+            // every voice gets the same input
+            for (i, input) in inputs.iter_mut().enumerate() {
+                let frame = [channels[0][block_start + i], channels[1][block_start + i]];
+                *input = VoiceVector::from_array(array::from_fn(|lane| frame[lane % 2]));
+            }
+
+            self.processor.snapshot_params();
+
+            let num_voices = self.voice_handler.len();
+            let num_vectors = (num_voices + VOICES_PER_VECTOR - 1) / VOICES_PER_VECTOR;
+
+            for vector_idx in 0..num_vectors {
+                self.processor.process(inputs, voices_output, vector_idx, false);
+
+                let first_voice = vector_idx * VOICES_PER_VECTOR;
+                for voice_idx in first_voice..num_voices.min(first_voice + VOICES_PER_VECTOR) {
+                    let lane = 2 * (voice_idx - first_voice);
+                    let voice_output = voices_output
+                        .iter()
+                        .map(|frame| f32x2::from_array([frame[lane], frame[lane + 1]]));
+
+                    self.voice_handler.track_level(voice_idx, voice_output);
                 }
-                next_event = context.next_event();
+
+                output.iter_mut().zip(voices_output.iter()).for_each(|(out, &v)| *out += v);
             }
 
-            let input_frame_simd = unsafe { input_frame.to_simd_unchecked() };
+            // mix the voices down
+            for (i, frame) in output.iter().enumerate() {
+                let [l, r] = frame
+                    .as_array()
+                    .array_chunks::<2>()
+                    .fold([0.; 2], |[l, r], &[voice_l, voice_r]| [l + voice_l, r + voice_r]);
 
-            input_frame.from_simd(
-                (0..self.voice_handler.len()).map( |i|
-                    self.processor.process(input_frame_simd, i, false)
-                ).sum()
-            );
+                channels[0][block_start + i] = l;
+                channels[1][block_start + i] = r;
+            }
+
+            block_start = block_end;
+        }
+
+        // events of empty buffers, and those timed past the end of the buffer, still count
+        while let Some(event) = next_event {
+            self.handle_event(event, context);
+            next_event = context.next_event();
+        }
+
+        let last_sample = num_samples.saturating_sub(1) as u32;
+
+        // going backwards so that swap-removed voices have already been checked
+        for i in (0..self.voice_handler.len()).rev() {
+            if self.voice_handler.is_releasing(i) && self.processor.voice_finished(i) {
+                let key = self.voice_handler.remove(i);
+                self.processor.remove_voice(i);
+                context.send_event(key.terminated(last_sample));
+            }
         }
+
         ProcessStatus::Normal
     }
 }
@@ -147,12 +596,19 @@ impl<T: SeenthStandAlonePlugin, const N: usize> ClapPlugin for SeenthPlugin<T, N
 
     const CLAP_SUPPORT_URL: Option<&'static str> = None;
 
-    const CLAP_FEATURES: &'static [ClapFeature] = &[];    
+    const CLAP_FEATURES: &'static [ClapFeature] = &[
+        ClapFeature::Instrument,
+        ClapFeature::Synthesizer,
+        ClapFeature::Stereo,
+    ];
+
+    const CLAP_POLY_MODULATION_CONFIG: Option<PolyModulationConfig> = Some(PolyModulationConfig {
+        max_voice_capacity: N as u32,
+        supports_overlapping_voices: true,
+    });
 }
 
+nih_export_clap!(SeenthPlugin<wavetable_oscillator::WTOscParams>);
 nih_export_vst3!(SeenthPlugin<wavetable_oscillator::WTOscParams>);
 
-// find a way to use SIMD generically over vector size
 // build audio graph GUI
-// add different voice prioritization algorithms
-// support pitch wheel, mod wheel and MIDI CCs
@@ -0,0 +1,167 @@
+use super::*;
+use nodes::midi_learn::CcBinding;
+use voices::{VoiceExpression, VoiceHandler};
+use serde::{Deserialize, Serialize};
+
+pub const MOD_WHEEL: u8 = 1;
+pub const BREATH: u8 = 2;
+pub const EXPRESSION: u8 = 11;
+
+/// Where a modulation route takes its value from
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ModSource {
+    Cc(u8),
+    /// polyphonic/MPE pressure
+    Pressure,
+    /// polyphonic/MPE brightness (CC 74 on MPE member channels)
+    Brightness,
+}
+
+impl ModSource {
+    fn name(self) -> String {
+        match self {
+            ModSource::Cc(MOD_WHEEL) => "Mod Wheel".into(),
+            ModSource::Cc(BREATH) => "Breath".into(),
+            ModSource::Cc(EXPRESSION) => "Expression".into(),
+            ModSource::Cc(cc) => format!("CC {cc}"),
+            ModSource::Pressure => "Pressure".into(),
+            ModSource::Brightness => "Brightness".into(),
+        }
+    }
+}
+
+/// Routes a modulation source to a modulation target of the top-level node
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ModRoute {
+    pub source: ModSource,
+    /// ID of the target parameter
+    pub target: String,
+    /// normalized offset applied when the source is all the way up
+    pub depth: f32,
+}
+
+/// Audio thread side of the modulation routing. Keeps track of the
+/// values of the modulation sources, and turns them into modulation offsets
+pub struct ModulationState {
+    /// `None` for CCs that haven't been received yet
+    cc_values: [Option<f32>; 128],
+}
+
+impl Default for ModulationState {
+    fn default() -> Self {
+        Self {
+            cc_values: [None; 128],
+        }
+    }
+}
+
+impl ModulationState {
+    pub fn set_cc(&mut self, cc: u8, value: f32) {
+        if let Some(cc_value) = self.cc_values.get_mut(cc as usize) {
+            *cc_value = Some(value);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.cc_values.fill(None);
+    }
+
+    fn cc_value(&self, cc: u8) -> Option<f32> {
+        self.cc_values.get(cc as usize).copied().flatten()
+    }
+
+    fn source_value(&self, source: ModSource, expression: &VoiceExpression) -> f32 {
+        match source {
+            ModSource::Cc(cc) => self.cc_value(cc).unwrap_or(0.),
+            ModSource::Pressure => expression.pressure,
+            ModSource::Brightness => expression.brightness,
+        }
+    }
+
+    /// Sends the offset of every one of `targets`, for each voice of `voices`, to
+    /// `processor`, along with the values of the targets bound to a CC.
+    /// The index of a target in `targets` is its `ModulationId`.
+    pub fn apply<const VOICES: usize>(
+        &self,
+        routes: &[ModRoute],
+        bindings: &[CcBinding],
+        targets: &[&str],
+        processor: &mut impl Processor,
+        voices: &VoiceHandler<VOICES>,
+    ) {
+        for (target_id, &target) in targets.iter().enumerate() {
+            let target_id = target_id as ModulationId;
+
+            for voice_idx in 0..voices.len() {
+                let expression = voices.expression(voice_idx);
+
+                let offset = routes
+                    .iter()
+                    .filter(|route| route.target == target)
+                    .map(|route| route.depth * self.source_value(route.source, expression))
+                    .sum::<f32>();
+
+                processor.set_modulation(voice_idx, target_id, offset);
+            }
+
+            // targets that aren't (or aren't anymore) bound to a CC are released
+            let value = bindings
+                .iter()
+                .find(|binding| binding.target == target)
+                .and_then(|binding| self.cc_value(binding.cc));
+
+            processor.override_target(target_id, value);
+        }
+    }
+}
+
+/// Editor for the modulation routes
+pub fn routes_ui(ui: &mut Ui, routes: &mut Vec<ModRoute>, targets: &[&str]) {
+    let mut removed = None;
+
+    for (i, route) in routes.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            ComboBox::from_id_source(ui.id().with(("source", i)))
+                .selected_text(route.source.name())
+                .show_ui(ui, |ui| {
+                    let sources = [ModSource::Pressure, ModSource::Brightness]
+                        .into_iter()
+                        .chain((0..128).map(ModSource::Cc));
+
+                    for source in sources {
+                        ui.selectable_value(&mut route.source, source, source.name());
+                    }
+                });
+
+            ComboBox::from_id_source(ui.id().with(("target", i)))
+                .selected_text(route.target.as_str())
+                .show_ui(ui, |ui| {
+                    for &target in targets {
+                        if ui.selectable_label(route.target == target, target).clicked() {
+                            route.target = target.into();
+                        }
+                    }
+                });
+
+            ui.add(Slider::new(&mut route.depth, -1.0..=1.0).text("Depth"));
+
+            if ui.button("Remove").clicked() {
+                removed = Some(i);
+            }
+        });
+    }
+
+    if let Some(i) = removed {
+        routes.remove(i);
+    }
+
+    if let Some(&target) = targets.first() {
+        if ui.button("Add Route").clicked() {
+            routes.push(ModRoute {
+                source: ModSource::Cc(MOD_WHEEL),
+                target: target.into(),
+                depth: 1.,
+            });
+        }
+    }
+}
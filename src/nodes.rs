@@ -12,10 +12,11 @@ use plugin_util::{
     parameter::{Modulable, ParamHandle},
 };
 
+use midi_learn::MidiLearn;
 use rtrb::{Consumer, Producer};
 pub use std::sync::Arc;
 
-use std::{any::Any, simd::f32x2};
+use std::{any::Any, simd::Simd};
 
 pub trait Processor {
 
@@ -23,19 +24,99 @@ pub trait Processor {
 
     fn remove_voice(&mut self, voice_idx: usize);
 
-    fn process(&mut self, input: f32x2, voice_idx: usize, editor_open: bool) -> f32x2;
+    /// The note of the voice at `voice_idx` has been released, start fading it out
+    fn release_voice(&mut self, voice_idx: usize);
+
+    /// Whether the (released) voice at `voice_idx` has gone silent, and can be removed
+    fn voice_finished(&self, voice_idx: usize) -> bool;
+
+    /// Smoothly detune the voice at `voice_idx` by `semitones` from the note it is playing
+    fn set_voice_tuning(&mut self, voice_idx: usize, semitones: f32);
+
+    /// Slide the pitch of the voice at `voice_idx` to `norm_freq`, in `glide_time` seconds
+    fn glide_voice(&mut self, voice_idx: usize, norm_freq: f32, glide_time: f32);
+
+    /// Offset the modulation target `target` of the voice at `voice_idx` by
+    /// `normalized_offset`, replacing the previous offset
+    fn set_modulation(&mut self, voice_idx: usize, target: ModulationId, normalized_offset: f32);
+
+    /// Offset the modulation target `target` of the voice at `voice_idx` by the host's
+    /// (polyphonic) modulation `normalized_offset`, on top of the offset set by
+    /// `set_modulation`, replacing the previous host offset
+    fn set_poly_modulation(
+        &mut self,
+        voice_idx: usize,
+        target: ModulationId,
+        normalized_offset: f32,
+    );
+
+    /// Set the modulation target `target` of every voice to `normalized_value`,
+    /// ignoring its parameter's value, or stop doing so, if `None`
+    fn override_target(&mut self, target: ModulationId, normalized_value: Option<f32>);
+
+    /// Take a snapshot of the (modulated) parameters of every voice, used
+    /// by `process` until the next call. Called before every (sub-)block.
+    fn snapshot_params(&mut self);
+
+    /// Render the voices of the vector at `vector_idx`, i. e. those starting at
+    /// `vector_idx * VOICES_PER_VECTOR`, into `outputs`, overwriting its contents.
+    /// `outputs` has the same length as `inputs`, which is never longer than
+    /// `MAX_BLOCK_SIZE`. Lanes of missing voices are left silent.
+    fn process(
+        &mut self,
+        inputs: &[VoiceVector],
+        outputs: &mut [VoiceVector],
+        vector_idx: usize,
+        editor_open: bool,
+    );
 
     fn initialize(&mut self, sample_rate: f32) -> (bool, u32);
 
     fn reset(&mut self);
 }
 
+/// Work too slow for the GUI and audio threads, run on the plugin's background thread
+pub struct BackgroundTask(Box<dyn FnOnce() + Send>);
+
+impl BackgroundTask {
+    pub fn run(self) {
+        (self.0)()
+    }
+}
+
+/// Hands tasks over to the plugin's background thread
+#[derive(Clone)]
+pub struct BackgroundExecutor(Arc<dyn Fn(BackgroundTask) + Send + Sync>);
+
+impl BackgroundExecutor {
+    pub fn new(execute: impl Fn(BackgroundTask) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(execute))
+    }
+
+    pub fn execute(&self, task: impl FnOnce() + Send + 'static) {
+        (self.0)(BackgroundTask(Box::new(task)))
+    }
+}
+
+/// Index of a modulatable parameter in its node's `SeenthNode::modulation_targets`.
+/// In the audio graph, nodes take consecutive ranges of IDs, see `ProcessSchedule::push`
+pub type ModulationId = u32;
+
 pub type ProcessNode = dyn Processor + Send;
 
 pub trait SeenthNode: Params + Any {
     fn type_name(&self) -> &'static str;
 
-    fn ui(&self, ui: &mut Ui, setter: &ParamSetter) -> Response;
+    fn ui(
+        &self,
+        ui: &mut Ui,
+        setter: &ParamSetter,
+        midi_learn: &MidiLearn,
+        executor: &BackgroundExecutor,
+    ) -> Response;
+
+    /// IDs of the parameters of this node that can be modulated
+    fn modulation_targets(&self) -> &'static [&'static str];
 
     fn processor_node(self: Arc<Self>) -> Box<ProcessNode>;
 }
@@ -48,6 +129,24 @@ pub trait SeenthStandAlonePlugin: SeenthNode + Default {
 }
 
 pub const MAX_POLYPHONY: usize = 16;
+/// Longest (sub-)block processors are given, parameters are
+/// snapshotted at least this often, and events never wait longer
+pub const MAX_BLOCK_SIZE: usize = 64;
+
+/// Number of `f32` lanes voices are rendered in, the `n`th voice of a vector
+/// takes lanes `2 * n` (left channel) and `2 * n + 1` (right channel)
+#[cfg(target_feature = "avx512f")]
+pub const LANES: usize = 16;
+#[cfg(all(target_feature = "avx", not(target_feature = "avx512f")))]
+pub const LANES: usize = 8;
+#[cfg(not(target_feature = "avx"))]
+pub const LANES: usize = 4;
+
+pub const VOICES_PER_VECTOR: usize = LANES / 2;
+pub const MAX_VOICE_VECTORS: usize = (MAX_POLYPHONY + VOICES_PER_VECTOR - 1) / VOICES_PER_VECTOR;
+
+/// Several voices, packed together
+pub type VoiceVector = Simd<f32, LANES>;
 
 type ModulableParamHandle<T> = Modulable<T, MAX_POLYPHONY>;
 
@@ -56,4 +155,5 @@ fn modulable<T: Param>(param: T) -> ModulableParamHandle<T> {
 }
 
 pub mod audio_graph;
+pub mod midi_learn;
 pub mod wavetable_oscillator;
\ No newline at end of file
@@ -1,11 +1,15 @@
 use super::*;
-use std::simd::f32x2;
+use std::ops::Range;
 
 #[derive(Default)]
 pub struct ProcessSchedule { 
     nodes: Vec<Box<dyn Processor + Send>>,
-    buffers: Vec<f32x2>,
+    /// input of every node, for the current block
+    buffers: Vec<[VoiceVector; MAX_BLOCK_SIZE]>,
     edges: Vec<Vec<usize>>,
+    /// modulation IDs of the targets of every node, the first
+    /// is that of the node's first target, see `target_node`
+    mod_ids: Vec<Range<ModulationId>>,
 }
 
 impl Processor for ProcessSchedule {
@@ -26,23 +30,92 @@ impl Processor for ProcessSchedule {
         }
     }
 
-    fn process(&mut self, _input: f32x2, voice_idx: usize, editor_open: bool) -> f32x2 {
+    fn release_voice(&mut self, voice_idx: usize) {
 
-        let mut out = f32x2::splat(0.);
+        for processor in self.nodes.iter_mut() {
+            processor.release_voice(voice_idx);
+        }
+    }
+
+    fn voice_finished(&self, voice_idx: usize) -> bool {
+
+        self.nodes.iter().all(|processor| processor.voice_finished(voice_idx))
+    }
+
+    fn set_voice_tuning(&mut self, voice_idx: usize, semitones: f32) {
+
+        for processor in self.nodes.iter_mut() {
+            processor.set_voice_tuning(voice_idx, semitones);
+        }
+    }
+
+    fn glide_voice(&mut self, voice_idx: usize, norm_freq: f32, glide_time: f32) {
+
+        for processor in self.nodes.iter_mut() {
+            processor.glide_voice(voice_idx, norm_freq, glide_time);
+        }
+    }
+
+    fn set_modulation(&mut self, voice_idx: usize, target: ModulationId, normalized_offset: f32) {
+
+        if let Some((processor, target)) = self.target_node(target) {
+            processor.set_modulation(voice_idx, target, normalized_offset);
+        }
+    }
+
+    fn set_poly_modulation(
+        &mut self,
+        voice_idx: usize,
+        target: ModulationId,
+        normalized_offset: f32,
+    ) {
+
+        if let Some((processor, target)) = self.target_node(target) {
+            processor.set_poly_modulation(voice_idx, target, normalized_offset);
+        }
+    }
+
+    fn override_target(&mut self, target: ModulationId, normalized_value: Option<f32>) {
+
+        if let Some((processor, target)) = self.target_node(target) {
+            processor.override_target(target, normalized_value);
+        }
+    }
+
+    fn snapshot_params(&mut self) {
+        self.nodes.iter_mut().for_each(|node| node.snapshot_params());
+    }
+
+    fn process(
+        &mut self,
+        _inputs: &[VoiceVector],
+        outputs: &mut [VoiceVector],
+        vector_idx: usize,
+        editor_open: bool,
+    ) {
+        let len = outputs.len();
+        outputs.fill(VoiceVector::splat(0.));
+
+        for buffer in self.buffers.iter_mut() {
+            buffer[..len].fill(VoiceVector::splat(0.));
+        }
+
+        let mut node_out = [VoiceVector::splat(0.); MAX_BLOCK_SIZE];
 
         for (i, (node, edges)) in self.nodes.iter_mut().zip(self.edges.iter()).enumerate() {
-            let node_out = node.process(self.buffers[i], voice_idx, editor_open);
+            let node_in = self.buffers[i];
+            node.process(&node_in[..len], &mut node_out[..len], vector_idx, editor_open);
 
             for &edge in edges {
-                if edge == usize::MAX {
-                    out += node_out;
+                let dest = if edge == usize::MAX {
+                    &mut *outputs
                 } else {
-                    self.buffers[edge] += node_out;
-                }
+                    &mut self.buffers[edge][..len]
+                };
+
+                dest.iter_mut().zip(node_out.iter()).for_each(|(dest, &out)| *dest += out);
             }
         }
-
-        out
     }
 
     fn initialize(&mut self, sample_rate: f32) -> (bool, u32) {
@@ -56,13 +129,25 @@ impl Processor for ProcessSchedule {
 }
 
 impl ProcessSchedule {
+    /// Adds a node, whose modulation targets have the IDs in `mod_ids`, in the same
+    /// order as its own, those must be the poly modulation IDs of its parameters too
     pub(super) fn push(
         &mut self, processor: Box<dyn Processor + Send>,
         outputs: Vec<usize>,
+        mod_ids: Range<ModulationId>,
     ) {
-        self.buffers.push(f32x2::splat(0.));
+        self.buffers.push([VoiceVector::splat(0.); MAX_BLOCK_SIZE]);
         self.nodes.push(processor.into());
         self.edges.push(outputs);
+        self.mod_ids.push(mod_ids);
+    }
+
+    /// The node owning the modulation target `target`, and the ID it gives it
+    fn target_node(&mut self, target: ModulationId) -> Option<(&mut ProcessNode, ModulationId)> {
+        let node_idx = self.mod_ids.iter().position(|ids| ids.contains(&target))?;
+        let first_id = self.mod_ids[node_idx].start;
+
+        Some((self.nodes[node_idx].as_mut(), target - first_id))
     }
 }
 
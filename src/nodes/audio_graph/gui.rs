@@ -14,15 +14,31 @@ impl SeenthParams {
         self.graph.borrow_mut().top_level_insert(node);
     }
 
+    /// Modulation ID of the first target of a node added to the graph now, nodes
+    /// take consecutive IDs, in the order they were added, see `build_audio_graph`
+    fn next_mod_id(&self) -> ModulationId {
+        self.graph
+            .borrow()
+            .iter()
+            .map(|node| node.modulation_targets().len() as ModulationId)
+            .sum()
+    }
+
     fn build_audio_graph(&self) -> ProcessSchedule {
         let graph = self.graph.borrow();
         let mut schedule = ProcessSchedule::default();
+        let mut first_mod_id = 0;
 
         for (node, edges) in graph.iter().zip(graph.edges().iter()) {
+            let num_targets = node.modulation_targets().len() as ModulationId;
+
             schedule.push(
                 node.clone().processor_node(),
                 edges.clone(),
+                first_mod_id..first_mod_id + num_targets,
             );
+
+            first_mod_id += num_targets;
         }
 
         schedule
@@ -34,14 +50,21 @@ impl SeenthNode for SeenthParams {
         "Synth"
     }
 
-    fn ui(&self, ui: &mut Ui, setter: &ParamSetter) -> Response {
+    fn ui(
+        &self,
+        ui: &mut Ui,
+        setter: &ParamSetter,
+        midi_learn: &MidiLearn,
+        executor: &BackgroundExecutor,
+    ) -> Response {
 
         SidePanel::new(Side::Left, "banana").show_inside(ui, |ui| {
 
             ui.add_space(40.);
 
             if ui.button("new WTOsc").clicked() {
-                self.insert_top_level_node(Arc::new(WTOscParams::default()));
+                let node = WTOscParams::with_mod_id_offset(self.next_mod_id());
+                self.insert_top_level_node(Arc::new(node));
             }
 
         }).response | CentralPanel::default().show_inside(ui, |ui| {
@@ -59,12 +82,16 @@ impl SeenthNode for SeenthParams {
                 Window::new(node_index.to_string())
                     .fixed_size((400., 500.))
                     .show(ui.ctx(), |ui| {
-                        node_params.ui(ui, setter);
+                        node_params.ui(ui, setter, midi_learn, executor);
                     });
             }
         }).response
     }
 
+    fn modulation_targets(&self) -> &'static [&'static str] {
+        &[]
+    }
+
     fn processor_node(self: Arc<Self>) -> Box<ProcessNode> {
         Box::new(self.schedule())
     }
@@ -0,0 +1,169 @@
+use super::*;
+use atomic_float::AtomicF32;
+use atomic_refcell::AtomicRef;
+use serde::{Deserialize, Serialize};
+use std::{
+    array,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Placeholder for "no CC received"
+const NO_CC: u8 = u8::MAX;
+
+/// A parameter whose value is set by a MIDI CC
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CcBinding {
+    pub cc: u8,
+    /// ID of the bound parameter
+    pub target: String,
+}
+
+/// MIDI learn state, shared between the editor and the audio thread.
+#[derive(Params)]
+pub struct MidiLearn {
+    #[persist = "midi_learn"]
+    bindings: AtomicRefCell<Vec<CcBinding>>,
+    /// ID of the parameter waiting to be bound to the next CC
+    learning: AtomicRefCell<Option<&'static str>>,
+    /// last CC received by the audio thread, `NO_CC` if none since the last check
+    last_cc: AtomicU8,
+    /// last value of every CC received by the audio thread, that the editor hasn't
+    /// set the bound parameter to yet, `NAN` if there is none
+    pending_values: [AtomicF32; 128],
+}
+
+impl Default for MidiLearn {
+    fn default() -> Self {
+        Self {
+            bindings: Default::default(),
+            learning: Default::default(),
+            last_cc: AtomicU8::new(NO_CC),
+            pending_values: array::from_fn(|_| AtomicF32::new(f32::NAN)),
+        }
+    }
+}
+
+impl MidiLearn {
+    /// Called from the audio thread on every CC event
+    pub fn cc_received(&self, cc: u8, value: f32) {
+        self.last_cc.store(cc, Ordering::Relaxed);
+
+        if let Some(pending) = self.pending_values.get(cc as usize) {
+            pending.store(value, Ordering::Relaxed);
+        }
+    }
+
+    /// The CC bound to every parameter, `None` if they are being edited
+    pub fn bindings(&self) -> Option<AtomicRef<Vec<CcBinding>>> {
+        self.bindings.try_borrow().ok()
+    }
+
+    fn bound_cc(&self, param_id: &str) -> Option<u8> {
+        self.bindings
+            .try_borrow()
+            .ok()?
+            .iter()
+            .find(|binding| binding.target == param_id)
+            .map(|binding| binding.cc)
+    }
+
+    /// Once unbound, the parameter keeps the value it was last set to, and the
+    /// audio thread stops overriding it, see `ModulationState::apply`
+    fn unlearn(&self, param_id: &str) {
+        // fails if the audio thread is reading the bindings, the user can just click again
+        if let Ok(mut bindings) = self.bindings.try_borrow_mut() {
+            bindings.retain(|binding| binding.target != param_id);
+        }
+    }
+
+    /// Binds the parameter being learned, if any, to the last received CC, if any,
+    /// then sets the bound parameters of `params` to the values their CCs were last
+    /// moved to, through the host, for it, and the editor, to see what's playing.
+    /// Must be called every editor frame.
+    pub fn poll(&self, setter: &ParamSetter, params: &impl Params) {
+        self.learn();
+
+        let Some(bindings) = self.bindings() else {
+            return;
+        };
+
+        let mut param_map = None;
+
+        for binding in bindings.iter() {
+            let Some(pending) = self.pending_values.get(binding.cc as usize) else {
+                continue;
+            };
+
+            let value = pending.swap(f32::NAN, Ordering::Relaxed);
+            if value.is_nan() {
+                continue;
+            }
+
+            let param = param_map
+                .get_or_insert_with(|| params.param_map())
+                .iter()
+                .find(|(id, ..)| *id == binding.target)
+                .map(|&(_, param, _)| param);
+
+            if let Some(param) = param {
+                // SAFETY: `param` comes from the plugin's own parameters, which outlive the editor
+                unsafe {
+                    setter.raw_context.raw_begin_set_parameter(param);
+                    setter.raw_context.raw_set_parameter_normalized(param, value);
+                    setter.raw_context.raw_end_set_parameter(param);
+                }
+            }
+        }
+    }
+
+    /// Binds the parameter being learned, if any, to the last received CC, if any
+    fn learn(&self) {
+        let mut learning = self.learning.borrow_mut();
+
+        let Some(param_id) = *learning else {
+            return;
+        };
+
+        // the audio thread is reading the bindings, try again next frame
+        let Ok(mut bindings) = self.bindings.try_borrow_mut() else {
+            return;
+        };
+
+        let cc = self.last_cc.swap(NO_CC, Ordering::Relaxed);
+
+        if cc != NO_CC {
+            bindings.retain(|binding| binding.target != param_id);
+            bindings.push(CcBinding {
+                cc,
+                target: param_id.into(),
+            });
+            *learning = None;
+        }
+    }
+
+    /// Adds a learn/unlearn context menu, for the parameter with the given ID, to `response`
+    pub fn context_menu(&self, response: Response, param_id: &'static str) -> Response {
+        response.context_menu(|ui| {
+            let mut learning = self.learning.borrow_mut();
+
+            if *learning == Some(param_id) {
+                ui.label("Move a MIDI controller...");
+                if ui.button("Cancel").clicked() {
+                    *learning = None;
+                    ui.close_menu();
+                }
+            } else if ui.button("Learn").clicked() {
+                *learning = Some(param_id);
+                self.last_cc.store(NO_CC, Ordering::Relaxed);
+                ui.close_menu();
+            }
+
+            if let Some(cc) = self.bound_cc(param_id) {
+                if ui.button(format!("Unlearn (CC {cc})")).clicked() {
+                    self.unlearn(param_id);
+                    ui.close_menu();
+                }
+            }
+        })
+    }
+}
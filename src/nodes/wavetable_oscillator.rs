@@ -1,15 +1,72 @@
 mod dsp;
+mod export;
+mod factory;
 mod gui;
+mod import;
 mod wavetable;
 
 use super::*;
 use dsp::WTOsc;
+use export::write_wav_wavetable;
+use factory::{factory_wavetable, FACTORY_TABLES};
+use rtrb::RingBuffer;
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use import::{is_wavetable_file, read_wavetable, ImportChannel};
+use wavetable::BandlimitedWaveTables;
 
 const FRAMES_PER_WT: usize = 256;
 const WAVE_FRAME_LEN: usize = 2048;
+const WAVETABLE_FOLDER_PATH: &str =
+    "C:\\Users\\etulyon1\\Documents\\Coding\\Krynth\\wavetables";
+const DEFAULT_WAVETABLE: &str = FACTORY_TABLES[0];
+/// Wavetables that can be waiting for the oscillator to pick them up
+const TABLE_QUEUE_LEN: usize = 4;
 
 type WaveFrame = [f32; WAVE_FRAME_LEN + 1];
 type WaveTable = [WaveFrame ; FRAMES_PER_WT];
+type TableQueue = (Producer<BandlimitedWaveTables>, Consumer<BandlimitedWaveTables>);
+
+/// A wavetable, and the name it was loaded with
+struct LoadedWavetable {
+    name: String,
+    frames: Vec<WaveFrame>,
+}
+
+impl LoadedWavetable {
+    fn default_wavetable() -> Self {
+        Self {
+            name: DEFAULT_WAVETABLE.into(),
+            frames: factory_wavetable(DEFAULT_WAVETABLE).unwrap(),
+        }
+    }
+}
+
+/// IDs of the parameters that can be modulated, a parameter's
+/// index in this list is its `ModulationId`
+const MOD_TARGETS: [&str; 7] = [
+    "level",
+    "pan",
+    "unison",
+    "frame",
+    "det_range",
+    "detune",
+    "release",
+];
+
+/// Per-voice normalized offsets of every modulation target
+type ModOffsets = [f32; MOD_TARGETS.len()];
+
+/// Normalized values replacing those of the modulation targets' parameters, if set
+type ModOverrides = [Option<f32>; MOD_TARGETS.len()];
+
+/// The poly modulation ID of a parameter is its `ModulationId`, offset by
+/// `mod_id_offset`, see `WTOscParams::with_mod_id_offset`
+fn poly_mod_id(mod_id_offset: ModulationId, param_id: &str) -> ModulationId {
+    mod_id_offset + MOD_TARGETS.iter().position(|&id| id == param_id).unwrap() as ModulationId
+}
 
 #[derive(Params)]
 pub struct WTOscParams {
@@ -25,13 +82,33 @@ pub struct WTOscParams {
     detune_range: ModulableParamHandle<FloatParam>,
     #[id = "detune"]
     detune: ModulableParamHandle<FloatParam>,
+    #[id = "release"]
+    release: FloatParam,
     #[persist = "wt_name"]
     wt_name: AtomicRefCell<String>,
-    wavetable: AtomicRefCell<Vec<WaveFrame>>,
+    /// the last wavetable loaded, new oscillators start with it
+    wavetable: Arc<Mutex<LoadedWavetable>>,
+    /// set when a wavetable is loaded in the background, for the editor to update `wt_name`
+    wt_name_outdated: Arc<AtomicBool>,
+    /// sends the tables of newly loaded wavetables to the oscillator, and
+    /// receives the ones it has swapped out, for them to be freed here
+    table_queue: Arc<Mutex<Option<TableQueue>>>,
+    /// channel of multichannel files wavetables are imported from
+    import_channel: AtomicRefCell<ImportChannel>,
+    /// where the current wavetable is exported to
+    export_path: AtomicRefCell<String>,
 }
 
 impl Default for WTOscParams {
     fn default() -> Self {
+        Self::with_mod_id_offset(0)
+    }
+}
+
+impl WTOscParams {
+    /// An oscillator whose parameters' poly modulation IDs start at `mod_id_offset`,
+    /// for them not to clash with those of other nodes of the plugin
+    pub fn with_mod_id_offset(mod_id_offset: ModulationId) -> Self {
         Self {
             level: modulable(
                 FloatParam::new(
@@ -43,48 +120,177 @@ impl Default for WTOscParams {
                         factor: 0.5,
                     },
                 )
-                .with_value_to_string(v2s_f32_rounded(3)),
+                .with_value_to_string(v2s_f32_rounded(3))
+                .with_poly_modulation_id(poly_mod_id(mod_id_offset, "level")),
             ),
 
             pan: Arc::new(modulable(
                 FloatParam::new("Pan", 0.5, FloatRange::Linear { min: 0., max: 1. })
-                    .with_value_to_string(v2s_f32_rounded(3)),
+                    .with_value_to_string(v2s_f32_rounded(3))
+                    .with_poly_modulation_id(poly_mod_id(mod_id_offset, "pan")),
             )),
 
-            num_unison_voices: modulable(IntParam::new(
-                "Unison",
-                1,
-                IntRange::Linear { min: 1, max: 16 },
-            )),
+            num_unison_voices: modulable(
+                IntParam::new("Unison", 1, IntRange::Linear { min: 1, max: 16 })
+                    .with_poly_modulation_id(poly_mod_id(mod_id_offset, "unison")),
+            ),
 
-            frame: modulable(IntParam::new(
-                "Frame",
-                0,
-                IntRange::Linear {
-                    min: 0,
-                    max: FRAMES_PER_WT as i32 - 1,
-                },
-            )),
+            frame: modulable(
+                IntParam::new(
+                    "Frame",
+                    0,
+                    IntRange::Linear {
+                        min: 0,
+                        max: FRAMES_PER_WT as i32 - 1,
+                    },
+                )
+                .with_poly_modulation_id(poly_mod_id(mod_id_offset, "frame")),
+            ),
 
             detune_range: modulable(
                 FloatParam::new("Spread", 2., FloatRange::Linear { min: 0., max: 48. })
-                    .with_value_to_string(v2s_f32_rounded(3)),
+                    .with_value_to_string(v2s_f32_rounded(3))
+                    .with_poly_modulation_id(poly_mod_id(mod_id_offset, "det_range")),
             ),
 
             detune: modulable(
                 FloatParam::new("Detune", 0.2, FloatRange::Linear { min: 0., max: 1. })
-                    .with_value_to_string(v2s_f32_rounded(3)),
+                    .with_value_to_string(v2s_f32_rounded(3))
+                    .with_poly_modulation_id(poly_mod_id(mod_id_offset, "detune")),
             ),
 
-            wt_name: AtomicRefCell::new("Basic Shapes".into()),
+            release: FloatParam::new(
+                "Release",
+                200.,
+                FloatRange::Skewed {
+                    min: 1.,
+                    max: 10_000.,
+                    factor: FloatRange::skew_factor(-2.),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(v2s_f32_rounded(1))
+            .with_poly_modulation_id(poly_mod_id(mod_id_offset, "release")),
+
+            wt_name: AtomicRefCell::new(DEFAULT_WAVETABLE.into()),
 
-            wavetable: AtomicRefCell::new(Vec::new()),
+            wavetable: Arc::new(Mutex::new(LoadedWavetable::default_wavetable())),
+            wt_name_outdated: Default::default(),
+            table_queue: Default::default(),
+            import_channel: AtomicRefCell::new(ImportChannel::Mix),
+            export_path: Default::default(),
         }
     }
-}
 
-impl WTOscParams {
     fn oscillator(self: Arc<Self>) -> WTOsc {
-        WTOsc::new(self)
+        let (sender, receiver) = RingBuffer::new(TABLE_QUEUE_LEN);
+        let (garbage_sender, garbage_receiver) = RingBuffer::new(TABLE_QUEUE_LEN);
+        *self.table_queue.lock() = Some((sender, garbage_receiver));
+
+        WTOsc::new(self, receiver, garbage_sender)
+    }
+
+    /// Frees the tables the oscillator isn't playing anymore
+    fn free_old_tables(&self) {
+        free_old_tables(&mut self.table_queue.lock());
+    }
+
+    /// Loads the wavetable called `name`, factory or a file in the wavetable folder, and computes its
+    /// tables, in the background, then hands them over to the oscillator, which plays them right away.
+    /// `wt_name` only changes once it's loaded, see `update_wt_name`
+    fn load_wavetable(&self, name: String, executor: &BackgroundExecutor) {
+        let wavetable = self.wavetable.clone();
+        let wt_name_outdated = self.wt_name_outdated.clone();
+        let table_queue = self.table_queue.clone();
+        let channel = *self.import_channel.borrow();
+
+        executor.execute(move || {
+            let frames = match read_named_wavetable(&name, channel) {
+                Ok(frames) => frames,
+                Err(err) => {
+                    nih_log!("couldn't load wavetable {name}: {err}");
+                    return;
+                }
+            };
+
+            let mut tables = BandlimitedWaveTables::default();
+            tables.set_wavetable(frames.as_slice().try_into().unwrap());
+
+            let mut table_queue = table_queue.lock();
+            free_old_tables(&mut table_queue);
+
+            if let Some((sender, _)) = table_queue.as_mut() {
+                if sender.push(tables).is_err() {
+                    nih_log!("the oscillator isn't picking up new wavetables, dropping one");
+                }
+            }
+
+            *wavetable.lock() = LoadedWavetable { name, frames };
+            wt_name_outdated.store(true, Ordering::Release);
+        });
+    }
+
+    /// Sets `wt_name` to the name of the last wavetable loaded, if it has changed
+    fn update_wt_name(&self) {
+        if self.wt_name_outdated.swap(false, Ordering::Acquire) {
+            *self.wt_name.borrow_mut() = self.wavetable.lock().name.clone();
+        }
+    }
+
+    /// Loads the wavetable named by `wt_name`, when it was restored with the plugin's
+    /// state, and isn't the one loaded. If it can't be, `wt_name` names the loaded one again.
+    fn load_persisted_wavetable(&self) {
+        let Ok(mut name) = self.wt_name.try_borrow_mut() else {
+            return;
+        };
+
+        let mut wavetable = self.wavetable.lock();
+        if *name == wavetable.name {
+            return;
+        }
+
+        match read_named_wavetable(&name, *self.import_channel.borrow()) {
+            Ok(frames) => *wavetable = LoadedWavetable { name: name.clone(), frames },
+            Err(err) => {
+                nih_log!("couldn't load wavetable {}: {err}", *name);
+                *name = wavetable.name.clone();
+            }
+        }
+    }
+
+    /// Writes the current wavetable to `path`, in the background, see `write_wav_wavetable`
+    fn export_wavetable(&self, path: String, executor: &BackgroundExecutor) {
+        let wavetable = self.wavetable.clone();
+
+        executor.execute(move || {
+            // don't hold the lock while writing, the editor needs it to draw the wavetable
+            let frames = wavetable.lock().frames.clone();
+
+            if let Err(err) = write_wav_wavetable(&path, &frames) {
+                nih_log!("couldn't export wavetable to {path}: {err}");
+            }
+        });
+    }
+}
+
+/// Reads the wavetable called `name`, factory or a file in the wavetable folder
+fn read_named_wavetable(name: &str, channel: ImportChannel) -> Result<Vec<WaveFrame>, String> {
+    if let Some(frames) = factory_wavetable(name) {
+        return Ok(frames);
+    }
+
+    let mut path = format!("{WAVETABLE_FOLDER_PATH}\\{name}");
+
+    // names saved by older versions didn't have the .WAV extension
+    if Path::new(name).extension().is_none() {
+        path.push_str(".WAV");
+    }
+
+    read_wavetable(path, channel)
+}
+
+fn free_old_tables(table_queue: &mut Option<TableQueue>) {
+    if let Some((_, garbage_receiver)) = table_queue {
+        while garbage_receiver.pop().is_ok() {}
     }
 }
@@ -1,16 +1,24 @@
 use super::{
     wavetable::{BandlimitedWaveTables, PHASE_RANGE},
-    WTOscParams, *,
+    ModOffsets, ModOverrides, WTOscParams, *,
 };
 
-use std::{iter, ops::Add, simd::{usizex2, StdFloat, simd_swizzle}};
+use std::simd::{
+    f32x2, simd_swizzle, usizex2, LaneCount, Simd, SimdElement, SimdPartialOrd, SimdUint,
+    StdFloat, SupportedLaneCount,
+};
 
 use arrayvec::ArrayVec;
 use plugin_util::dsp::semitones;
 use rand::random;
 
 const MAX_UNISON: usize = 16;
+/// Gain under which a released voice is considered silent (-80dB)
+const SILENCE: f32 = 1e-4;
+/// Time constant of the pitch smoothing filter, in seconds
+const PITCH_SMOOTHING_TIME: f32 = 0.005;
 
+#[derive(Default, Clone, Copy)]
 struct WTOscModValues {
     level: f32x2,
     pan: f32x2,
@@ -21,209 +29,593 @@ struct WTOscModValues {
     stereo_pos: f32x2,
 }
 
-impl WTOscParams {
-    fn modulated(&self, voice_idx: usize) -> WTOscModValues {
-        let [lvl_l, lvl_r] = self.detune.get_value(voice_idx);
-        let [pan_l, pan_r] = self.pan.get_value(voice_idx);
+/// Offsets both channels of a stereo value by `offset`, in the normalized
+/// domain, replacing them with `overridden` first, if it is set
+fn offset_float(
+    param: &FloatParam,
+    value: [f32; 2],
+    overridden: Option<f32>,
+    offset: f32,
+) -> [f32; 2] {
+    match overridden {
+        Some(normalized) => [param.preview_plain((normalized + offset).clamp(0., 1.)); 2],
+        None if offset == 0. => value,
+        None => value
+            .map(|v| param.preview_plain((param.preview_normalized(v) + offset).clamp(0., 1.))),
+    }
+}
 
-        let stereo_pos = [1. - lvl_l, lvl_r].into();
+/// Same as `offset_float` but for integer parameters
+fn offset_int(
+    param: &IntParam,
+    value: [i32; 2],
+    overridden: Option<f32>,
+    offset: f32,
+) -> [i32; 2] {
+    match overridden {
+        Some(normalized) => [param.preview_plain((normalized + offset).clamp(0., 1.)); 2],
+        None if offset == 0. => value,
+        None => value
+            .map(|v| param.preview_plain((param.preview_normalized(v) + offset).clamp(0., 1.))),
+    }
+}
+
+impl WTOscParams {
+    fn modulated(
+        &self,
+        voice_idx: usize,
+        offsets: &ModOffsets,
+        overrides: &ModOverrides,
+    ) -> WTOscModValues {
+        let [level, pan, unison, frame, detune_range, detune, _release] = *offsets;
+        let [level_o, pan_o, unison_o, frame_o, detune_range_o, detune_o, _release_o] = *overrides;
+
+        let [detune_l, detune_r] =
+            offset_float(&self.detune, self.detune.get_value(voice_idx), detune_o, detune);
+        let [pan_l, pan_r] = offset_float(&self.pan, self.pan.get_value(voice_idx), pan_o, pan);
+
+        let stereo_pos = [1. - detune_l, detune_r].into();
         let pan = [1. - pan_l, pan_r].into();
 
         let [frame_l, frame_r] = self.frame.get_value(voice_idx);
+        let [frame_l, frame_r] = offset_int(
+            &self.frame,
+            [frame_l as i32, frame_r as i32],
+            frame_o,
+            frame,
+        );
+
         let [unison_l, unison_r] = self.num_unison_voices.get_value(voice_idx);
+        let [unison_l, unison_r] = offset_int(
+            &self.num_unison_voices,
+            [unison_l as i32, unison_r as i32],
+            unison_o,
+            unison,
+        );
 
         WTOscModValues {
-            level: self.level.get_value(voice_idx).into(),
+            level: offset_float(&self.level, self.level.get_value(voice_idx), level_o, level)
+                .into(),
             pan,
             num_unison_voices: [unison_l as usize, unison_r as usize].into(),
             frame: [frame_l as usize, frame_r as usize].into(),
-            detune_range: self.detune_range.get_value(voice_idx).into(),
-            detune: self.detune.get_value(voice_idx).into(),
+            detune_range: offset_float(
+                &self.detune_range,
+                self.detune_range.get_value(voice_idx),
+                detune_range_o,
+                detune_range,
+            )
+            .into(),
+            detune: [detune_l, detune_r].into(),
             stereo_pos,
         }
     }
+
+    /// Release time, in seconds, of the voice with the given modulation
+    fn release_time(&self, offsets: &ModOffsets, overrides: &ModOverrides) -> f32 {
+        let [.., offset] = *offsets;
+        let [.., overridden] = *overrides;
+
+        let [release, _] =
+            offset_float(&self.release, [self.release.value(); 2], overridden, offset);
+        release * 0.001
+    }
 }
 
-/// Describes a wavetable oscillator
-#[derive(Default)]
-struct Oscillator {
-    pub phase: f32x2,
-    pub phase_delta: f32x2,
+/// Copies the lanes of the voice in `src_slot` of `src` to those of the voice in `dst_slot` of `dst`
+#[inline]
+fn copy_lanes<T: SimdElement, const LANES: usize>(
+    dst: &mut Simd<T, LANES>,
+    dst_slot: usize,
+    src: &Simd<T, LANES>,
+    src_slot: usize,
+) where
+    LaneCount<LANES>: SupportedLaneCount,
+{
+    dst[2 * dst_slot] = src[2 * src_slot];
+    dst[2 * dst_slot + 1] = src[2 * src_slot + 1];
 }
 
-impl Oscillator {
-    fn new(phase: f32) -> Self {
-        Self {
-            phase: f32x2::splat(phase),
-            ..Default::default()
-        }
-    }
+/// Sets the lanes of the voice in `slot` of `vector` to `value`
+#[inline]
+fn set_lanes<T: SimdElement, const LANES: usize>(
+    vector: &mut Simd<T, LANES>,
+    slot: usize,
+    [l, r]: [T; 2],
+) where
+    LaneCount<LANES>: SupportedLaneCount,
+{
+    vector[2 * slot] = l;
+    vector[2 * slot + 1] = r;
+}
 
+/// Describes a wavetable oscillator
+#[derive(Default, Clone, Copy)]
+struct Oscillator<const LANES: usize>
+where
+    LaneCount<LANES>: SupportedLaneCount,
+{
+    pub phase: Simd<f32, LANES>,
+    pub phase_delta: Simd<f32, LANES>,
+}
+
+impl<const LANES: usize> Oscillator<LANES>
+where
+    LaneCount<LANES>: SupportedLaneCount,
+{
     #[inline]
     fn get_sample_from_table(
         &self,
         table: &BandlimitedWaveTables,
-        frame: usizex2,
-    ) -> f32x2 {
+        frame: Simd<usize, LANES>,
+    ) -> Simd<f32, LANES> {
         table.get_sample(self.phase, frame, self.phase_delta)
     }
 
     #[inline]
-    fn update_phase(&mut self, phase_delta: f32x2) {
+    fn update_phase(&mut self, phase_delta: Simd<f32, LANES>) {
         self.phase_delta = phase_delta;
-        self.phase = (self.phase + self.phase_delta) % f32x2::splat(PHASE_RANGE);
+        self.phase = (self.phase + self.phase_delta) % Simd::splat(PHASE_RANGE);
     }
 }
 
-#[derive(Default)]
-struct WTOscVoice {
-    base_phase_delta: f32x2,
-    inv_num_steps: f32x2, // -2. / (self.oscillators.len() - 1)
-    oscillators: ArrayVec<Oscillator, MAX_UNISON>,
+/// `LANES / 2` voices, rendered together, see `VoiceVector`
+#[derive(Default, Clone)]
+struct WTOscVoice<const LANES: usize>
+where
+    LaneCount<LANES>: SupportedLaneCount,
+{
+    oscillators: [Oscillator<LANES>; MAX_UNISON],
+    /// number of oscillators of the voice with the most unison voices
+    num_oscillators: usize,
+    num_unison_voices: Simd<usize, LANES>,
+    /// frequency of every oscillator, relative to its voice's (unison detune)
+    unison_tune: [Simd<f32, LANES>; MAX_UNISON],
+    /// gain of every oscillator (unison stereo spread), zero for unused ones
+    unison_gain: [Simd<f32, LANES>; MAX_UNISON],
+    frame: Simd<usize, LANES>,
+    /// level and panning
+    level: Simd<f32, LANES>,
+    base_phase_delta: Simd<f32, LANES>,
+    gain: Simd<f32, LANES>,
+    release_coef: Simd<f32, LANES>,
+    pitch: Simd<f32, LANES>,
+    target_pitch: Simd<f32, LANES>,
+    pitch_smoothing_coef: f32,
+    target_phase_delta: Simd<f32, LANES>,
+    /// how much `base_phase_delta` is multiplied by every sample while gliding
+    glide_step: Simd<f32, LANES>,
+    glide_samples_left: Simd<f32, LANES>,
+    /// one bit per slot, set for voices that haven't started playing yet
+    unstarted: u32,
 }
 
-impl WTOscVoice {
-    fn new(base_phase_delta: f32) -> Self {
+impl<const LANES: usize> WTOscVoice<LANES>
+where
+    LaneCount<LANES>: SupportedLaneCount,
+{
+    fn new(pitch_smoothing_coef: f32) -> Self {
         Self {
-            oscillators: Default::default(),
-            base_phase_delta: f32x2::splat(base_phase_delta),
+            pitch_smoothing_coef,
             ..Default::default()
         }
     }
 
+    /// Copies the voice in `src_slot` of `src` to `dst_slot`
+    fn copy_voice(&mut self, dst_slot: usize, src: &Self, src_slot: usize) {
+        for (dst_osc, src_osc) in self.oscillators.iter_mut().zip(src.oscillators.iter()) {
+            copy_lanes(&mut dst_osc.phase, dst_slot, &src_osc.phase, src_slot);
+            copy_lanes(&mut dst_osc.phase_delta, dst_slot, &src_osc.phase_delta, src_slot);
+        }
+
+        for (dst, src) in self.unison_tune.iter_mut().zip(src.unison_tune.iter()) {
+            copy_lanes(dst, dst_slot, src, src_slot);
+        }
+
+        for (dst, src) in self.unison_gain.iter_mut().zip(src.unison_gain.iter()) {
+            copy_lanes(dst, dst_slot, src, src_slot);
+        }
+
+        copy_lanes(&mut self.num_unison_voices, dst_slot, &src.num_unison_voices, src_slot);
+        copy_lanes(&mut self.frame, dst_slot, &src.frame, src_slot);
+        copy_lanes(&mut self.level, dst_slot, &src.level, src_slot);
+        copy_lanes(&mut self.base_phase_delta, dst_slot, &src.base_phase_delta, src_slot);
+        copy_lanes(&mut self.gain, dst_slot, &src.gain, src_slot);
+        copy_lanes(&mut self.release_coef, dst_slot, &src.release_coef, src_slot);
+        copy_lanes(&mut self.pitch, dst_slot, &src.pitch, src_slot);
+        copy_lanes(&mut self.target_pitch, dst_slot, &src.target_pitch, src_slot);
+        copy_lanes(&mut self.target_phase_delta, dst_slot, &src.target_phase_delta, src_slot);
+        copy_lanes(&mut self.glide_step, dst_slot, &src.glide_step, src_slot);
+        copy_lanes(&mut self.glide_samples_left, dst_slot, &src.glide_samples_left, src_slot);
+
+        let unstarted = (src.unstarted >> src_slot) & 1;
+        self.unstarted = (self.unstarted & !(1 << dst_slot)) | (unstarted << dst_slot);
+
+        self.num_oscillators = self.num_unison_voices.reduce_max();
+    }
+
+    /// Silences the voice in `slot`, making it available to a new voice
+    fn clear_voice(&mut self, slot: usize) {
+        self.copy_voice(slot, &Self::default(), 0);
+    }
+
+    /// Starts playing a new voice in `slot`
+    fn start_voice(&mut self, slot: usize, base_phase_delta: f32) {
+        self.clear_voice(slot);
+
+        set_lanes(&mut self.base_phase_delta, slot, [base_phase_delta; 2]);
+        set_lanes(&mut self.target_phase_delta, slot, [base_phase_delta; 2]);
+        set_lanes(&mut self.gain, slot, [1.; 2]);
+        set_lanes(&mut self.release_coef, slot, [1.; 2]);
+        set_lanes(&mut self.pitch, slot, [1.; 2]);
+        set_lanes(&mut self.target_pitch, slot, [1.; 2]);
+
+        self.unstarted |= 1 << slot;
+    }
+
+    fn set_tuning(&mut self, slot: usize, semitones_offset: f32) {
+        let pitch = semitones(semitones_offset);
+        set_lanes(&mut self.target_pitch, slot, [pitch; 2]);
+
+        // voices that haven't started playing yet shouldn't glide
+        if (self.unstarted >> slot) & 1 == 1 {
+            set_lanes(&mut self.pitch, slot, [pitch; 2]);
+        }
+    }
+
+    /// Slide the voice in `slot` exponentially to `phase_delta` in `num_samples`
+    fn glide_to(&mut self, slot: usize, phase_delta: f32, num_samples: f32) {
+        set_lanes(&mut self.target_phase_delta, slot, [phase_delta; 2]);
+
+        if num_samples < 1. {
+            set_lanes(&mut self.base_phase_delta, slot, [phase_delta; 2]);
+            set_lanes(&mut self.glide_samples_left, slot, [0.; 2]);
+        } else {
+            let ratio = phase_delta / self.base_phase_delta[2 * slot];
+            set_lanes(&mut self.glide_step, slot, [ratio.powf(num_samples.recip()); 2]);
+            set_lanes(&mut self.glide_samples_left, slot, [num_samples.trunc(); 2]);
+        }
+    }
+
+    /// Advance gliding and pitch smoothing, and return the current (non-detuned) phase increment
+    #[inline]
+    fn next_phase_delta(&mut self) -> Simd<f32, LANES> {
+        let zero = Simd::splat(0.);
+
+        let gliding = self.glide_samples_left.simd_gt(zero);
+        self.glide_samples_left =
+            gliding.select(self.glide_samples_left - Simd::splat(1.), self.glide_samples_left);
+
+        let arrived = self.glide_samples_left.simd_le(zero);
+        let glided =
+            arrived.select(self.target_phase_delta, self.base_phase_delta * self.glide_step);
+        self.base_phase_delta = gliding.select(glided, self.base_phase_delta);
+
+        self.pitch = self.target_pitch
+            + (self.pitch - self.target_pitch) * Simd::splat(self.pitch_smoothing_coef);
+        self.base_phase_delta * self.pitch
+    }
+
+    /// Start decaying the voice in `slot` exponentially, reaching `SILENCE` after `num_samples`
+    fn release(&mut self, slot: usize, num_samples: f32) {
+        set_lanes(&mut self.release_coef, slot, [SILENCE.powf(num_samples.max(1.).recip()); 2]);
+    }
+
+    fn is_silent(&self, slot: usize) -> bool {
+        self.gain[2 * slot] < SILENCE
+    }
+
     #[inline]
-    fn update_num_unison_voices(&mut self, num_voices: usizex2) {
+    fn update_num_unison_voices(&mut self, slot: usize, num_voices: usizex2) {
         // TODO?: You can't really stereo modulate the number of unison voices
         // (or can you?), so, for now, just don't, and use only the left value.
 
         let new = num_voices.to_array()[0];
 
-        let current = self.oscillators.len();
-        if new == current {
-            return;
-        }
+        let current = self.num_unison_voices[2 * slot];
+        set_lanes(&mut self.num_unison_voices, slot, [new; 2]);
 
         // TODO: use a thread_rng and pass it in here.
 
-        if new > current {
-            self.oscillators.extend(
-                iter::repeat_with(|| Oscillator::new(random::<f32>() * PHASE_RANGE))
-                    .take(new - current),
-            );
-        } else {
-            self.oscillators.truncate(new);
+        for osc in self.oscillators[current.min(new)..new].iter_mut() {
+            set_lanes(&mut osc.phase, slot, [random::<f32>() * PHASE_RANGE; 2]);
         }
-
-        self.inv_num_steps = f32x2::splat(-2.) / (num_voices - usizex2::splat(1)).cast();
     }
 
+    /// Lays out the unison voices of the voice in `slot`, symmetrically
+    /// around its pitch, the center one (if any) first, then in pairs
     #[inline]
-    fn update_phases(&mut self, detune_range: f32x2) {
-        let odd = self.oscillators.len() & 1;
-        if odd == 1 {
-            self.oscillators[0].update_phase(self.base_phase_delta);
+    fn update_unison(&mut self, slot: usize, detune_range: f32x2, stereo_pos: f32x2) {
+        fn x2semitones(val: f32x2) -> [f32; 2] {
+            val.to_array().map(semitones)
         }
-        
-        fn x2semitones(val: f32x2) -> f32x2 {
-            let &[l, r] = val.as_array();
-            f32x2::from_array([semitones(l), semitones(r)])
+
+        let pan = (stereo_pos * f32x2::splat(0.5)).sqrt();
+        let rev_pan = simd_swizzle!(pan, [1, 0]);
+
+        let num_voices = self.num_unison_voices[2 * slot];
+        let odd = num_voices & 1;
+
+        if odd == 1 {
+            set_lanes(&mut self.unison_tune[0], slot, [1.; 2]);
+            set_lanes(&mut self.unison_gain[0], slot, [1.; 2]);
         }
 
-        let mut tune = x2semitones(detune_range);
-        let tune_delta = x2semitones(detune_range * self.inv_num_steps);
+        let inv_num_steps = f32x2::splat(-2. / (num_voices as f32 - 1.));
+
+        let pairs = self.unison_tune[odd..num_voices]
+            .array_chunks_mut::<2>()
+            .zip(self.unison_gain[odd..num_voices].array_chunks_mut::<2>());
+
+        for (i, ([tune1, tune2], [gain1, gain2])) in pairs.enumerate() {
+            let step = f32x2::splat(1.) + f32x2::splat(i as f32) * inv_num_steps;
+            let tune = x2semitones(detune_range * step);
+
+            set_lanes(tune1, slot, tune);
+            set_lanes(tune2, slot, tune.map(f32::recip));
+            set_lanes(gain1, slot, pan.to_array());
+            set_lanes(gain2, slot, rev_pan.to_array());
+        }
 
-        self.oscillators[odd..]
-            .array_chunks_mut()
-            .for_each(|[osc1, osc2]| {
-                osc1.update_phase(self.base_phase_delta * tune);
-                osc2.update_phase(self.base_phase_delta / tune);
-                tune = tune * tune_delta;
-            });
+        for gain in self.unison_gain[num_voices..].iter_mut() {
+            set_lanes(gain, slot, [0.; 2]);
+        }
     }
 
-    #[inline]
-    fn get_sample_from_table(
-        &self,
-        table: &BandlimitedWaveTables,
-        frame: usizex2,
-        detune: f32x2,
-    ) -> f32x2 {
-        let pan = (detune * f32x2::splat(0.5)).sqrt();
-        let rev_pan = simd_swizzle!(pan, [1, 0]);
+    /// Use `params` for the voice in `slot` until the next call
+    fn set_params(&mut self, slot: usize, params: WTOscModValues) {
+        self.update_num_unison_voices(slot, params.num_unison_voices);
+        self.update_unison(slot, params.detune_range * params.detune, params.stereo_pos);
 
-        let odd = self.oscillators.len() & 1;
-        let accumulator = if odd == 0 {
-            f32x2::from_array([0., 0.])
-        } else {
-            self.oscillators[0].get_sample_from_table(table, frame)
-        };
+        set_lanes(&mut self.frame, slot, params.frame.to_array());
+        set_lanes(&mut self.level, slot, (params.level * params.pan.sqrt()).to_array());
 
-        self.oscillators[odd..]
-            .array_chunks()
-            .map(|[voice1, voice2]| {
-                let y1 = voice1.get_sample_from_table(table, frame);
-                let y2 = voice2.get_sample_from_table(table, frame);
-                y1 * pan + y2 * rev_pan
-            })
-            .fold(accumulator, Add::add)
+        self.num_oscillators = self.num_unison_voices.reduce_max();
     }
 
     #[inline]
-    fn process(&mut self, params: WTOscModValues, table: &BandlimitedWaveTables) -> f32x2 {
-        self.update_num_unison_voices(params.num_unison_voices);
-        self.update_phases(params.detune_range * params.detune);
+    fn process(&mut self, outputs: &mut [Simd<f32, LANES>], table: &BandlimitedWaveTables) {
+        self.unstarted = 0;
+
+        let num_oscillators = self.num_oscillators;
+
+        for output in outputs {
+            let base_phase_delta = self.next_phase_delta();
+
+            let oscillators = self.oscillators[..num_oscillators]
+                .iter_mut()
+                .zip(self.unison_tune.iter().zip(self.unison_gain.iter()));
+
+            let mut sample = Simd::splat(0.);
 
-        let sample = self.get_sample_from_table(table, params.frame, params.stereo_pos);
+            for (osc, (&tune, &gain)) in oscillators {
+                osc.update_phase(base_phase_delta * tune);
+                sample += osc.get_sample_from_table(table, self.frame) * gain;
+            }
 
-        sample * params.level * params.pan.sqrt()
+            *output = sample * self.level * self.gain;
+            self.gain *= self.release_coef;
+        }
     }
 }
 
 pub struct WTOsc {
     params: Arc<WTOscParams>,
     wavetables: BandlimitedWaveTables,
-    voices: ArrayVec<WTOscVoice, MAX_POLYPHONY>,
+    /// receives the tables of newly loaded wavetables
+    table_receiver: Consumer<BandlimitedWaveTables>,
+    /// sends the swapped out tables back, to be freed off the audio thread
+    garbage_sender: Producer<BandlimitedWaveTables>,
+    voices: ArrayVec<WTOscVoice<LANES>, MAX_VOICE_VECTORS>,
+    num_voices: usize,
+    /// modulation offsets of every voice
+    modulation: ArrayVec<VoiceModulation, MAX_POLYPHONY>,
+    sample_rate: f32,
+    pitch_smoothing_coef: f32,
+    overrides: ModOverrides,
+}
+
+/// Modulation offsets of a voice, by source
+#[derive(Default, Clone, Copy)]
+struct VoiceModulation {
+    /// from the modulation routes
+    routes: ModOffsets,
+    /// from the host's polyphonic modulation
+    host: ModOffsets,
+}
+
+impl VoiceModulation {
+    fn offsets(&self) -> ModOffsets {
+        std::array::from_fn(|target| self.routes[target] + self.host[target])
+    }
+}
+
+/// Index of the vector of voices the voice at `voice_idx` is in, and its slot in that vector
+fn vector_slot(voice_idx: usize) -> (usize, usize) {
+    (voice_idx / VOICES_PER_VECTOR, voice_idx % VOICES_PER_VECTOR)
 }
 
 impl WTOsc {
-    pub(super) fn new(params: Arc<WTOscParams>) -> Self {
+    pub(super) fn new(
+        params: Arc<WTOscParams>,
+        table_receiver: Consumer<BandlimitedWaveTables>,
+        garbage_sender: Producer<BandlimitedWaveTables>,
+    ) -> Self {
         Self {
             wavetables: Default::default(),
+            table_receiver,
+            garbage_sender,
             params,
             voices: Default::default(),
+            num_voices: 0,
+            modulation: Default::default(),
+            sample_rate: 44100.,
+            pitch_smoothing_coef: 0.,
+            overrides: Default::default(),
         }
     }
+
+    fn voice(&mut self, voice_idx: usize) -> (&mut WTOscVoice<LANES>, usize) {
+        let (vector_idx, slot) = vector_slot(voice_idx);
+        (&mut self.voices[vector_idx], slot)
+    }
 }
 
 impl Processor for WTOsc {
     fn add_voice(&mut self, norm_freq: f32) {
-        let phase_delta = norm_freq * PHASE_RANGE;
-        self.voices.push(WTOscVoice::new(phase_delta));
+        let (vector_idx, slot) = vector_slot(self.num_voices);
+
+        if vector_idx == self.voices.len() {
+            self.voices.push(WTOscVoice::new(self.pitch_smoothing_coef));
+        }
+
+        self.voices[vector_idx].start_voice(slot, norm_freq * PHASE_RANGE);
+        self.modulation.push(Default::default());
+        self.num_voices += 1;
     }
 
     fn remove_voice(&mut self, voice_idx: usize) {
-        self.voices.swap_remove(voice_idx);
+        self.num_voices -= 1;
+        self.modulation.swap_remove(voice_idx);
+
+        let (last_vector, last_slot) = vector_slot(self.num_voices);
+
+        // move the last voice in the place of the removed one
+        if voice_idx != self.num_voices {
+            let last = self.voices[last_vector].clone();
+            let (vector, slot) = self.voice(voice_idx);
+            vector.copy_voice(slot, &last, last_slot);
+        }
+
+        if last_slot == 0 {
+            self.voices.pop();
+        } else {
+            self.voices[last_vector].clear_voice(last_slot);
+        }
     }
 
-    #[inline]
-    /// pre-condition: inputs.len() = number of voices in self
-    fn process(&mut self, _input: f32x2, voice_idx: usize, _editor_open: bool) -> f32x2 {
+    fn release_voice(&mut self, voice_idx: usize) {
+        let release_time = self
+            .params
+            .release_time(&self.modulation[voice_idx].offsets(), &self.overrides);
+        let num_samples = release_time * self.sample_rate;
 
-        self.voices[voice_idx].process(
-            self.params.modulated(voice_idx),
-            &self.wavetables
-        )
+        let (vector, slot) = self.voice(voice_idx);
+        vector.release(slot, num_samples);
     }
 
-    fn initialize(&mut self, _sample_rate: f32) -> (bool, u32) {
-        self.wavetables.set_wavetable(
-            self.params.wavetable.borrow().as_slice().try_into().unwrap()
-        );
+    fn voice_finished(&self, voice_idx: usize) -> bool {
+        let (vector_idx, slot) = vector_slot(voice_idx);
+        self.voices[vector_idx].is_silent(slot)
+    }
+
+    fn set_voice_tuning(&mut self, voice_idx: usize, semitones: f32) {
+        let (vector, slot) = self.voice(voice_idx);
+        vector.set_tuning(slot, semitones);
+    }
+
+    fn glide_voice(&mut self, voice_idx: usize, norm_freq: f32, glide_time: f32) {
+        let num_samples = glide_time * self.sample_rate;
+        let (vector, slot) = self.voice(voice_idx);
+        vector.glide_to(slot, norm_freq * PHASE_RANGE, num_samples);
+    }
+
+    fn set_modulation(&mut self, voice_idx: usize, target: ModulationId, normalized_offset: f32) {
+        if let Some(offset) = self.modulation[voice_idx].routes.get_mut(target as usize) {
+            *offset = normalized_offset;
+        }
+    }
+
+    fn set_poly_modulation(
+        &mut self,
+        voice_idx: usize,
+        target: ModulationId,
+        normalized_offset: f32,
+    ) {
+        if let Some(offset) = self.modulation[voice_idx].host.get_mut(target as usize) {
+            *offset = normalized_offset;
+        }
+    }
+
+    fn override_target(&mut self, target: ModulationId, normalized_value: Option<f32>) {
+        if let Some(value) = self.overrides.get_mut(target as usize) {
+            *value = normalized_value;
+        }
+    }
+
+    fn snapshot_params(&mut self) {
+        // pick up newly loaded wavetables, only if the old ones can be sent
+        // back, as they might be the last reference to their allocation
+        while !self.garbage_sender.is_full() {
+            let Ok(tables) = self.table_receiver.pop() else {
+                break;
+            };
+
+            let old_tables = std::mem::replace(&mut self.wavetables, tables);
+            // can't fail, `is_full` was checked above
+            let _ = self.garbage_sender.push(old_tables);
+        }
+
+        for (voice_idx, modulation) in self.modulation.iter().enumerate() {
+            let params =
+                self.params.modulated(voice_idx, &modulation.offsets(), &self.overrides);
+
+            let (vector_idx, slot) = vector_slot(voice_idx);
+            self.voices[vector_idx].set_params(slot, params);
+        }
+    }
+
+    #[inline]
+    fn process(
+        &mut self,
+        _inputs: &[VoiceVector],
+        outputs: &mut [VoiceVector],
+        vector_idx: usize,
+        _editor_open: bool,
+    ) {
+        match self.voices.get_mut(vector_idx) {
+            Some(voices) => voices.process(outputs, &self.wavetables),
+            None => outputs.fill(Simd::splat(0.)),
+        }
+    }
+
+    fn initialize(&mut self, sample_rate: f32) -> (bool, u32) {
+        self.sample_rate = sample_rate;
+        self.pitch_smoothing_coef = (-(PITCH_SMOOTHING_TIME * sample_rate).recip()).exp();
+        // the plugin's state might have just been restored
+        self.params.load_persisted_wavetable();
+
+        // keep the current tables if there is no (complete) wavetable to play
+        if let Ok(wavetable) = self.params.wavetable.lock().frames.as_slice().try_into() {
+            self.wavetables.set_wavetable(wavetable);
+        }
         (true, 0)
     }
 
     fn reset(&mut self) {
-        self.voices.clear()
+        self.voices.clear();
+        self.modulation.clear();
+        self.num_voices = 0;
     }
 }
 
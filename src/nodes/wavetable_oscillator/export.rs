@@ -0,0 +1,67 @@
+use super::*;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use import::{insert_riff_chunk, FORMAT_CHUNK, SERUM_CHUNK};
+use std::{fs::write, io::Cursor, path::Path};
+
+/// Sample rate written in exported files, meaningless for wavetables, but expected by most synths
+const EXPORT_SAMPLE_RATE: u32 = 44100;
+
+/// Writes `wavetable` to the WAV file at `path`, in 32-bit floats, with a `clm ` chunk
+/// giving its frame length, for other synths to split it in frames as we do
+pub(super) fn write_wav_wavetable(
+    path: impl AsRef<Path>,
+    wavetable: &[WaveFrame],
+) -> Result<(), String> {
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate: EXPORT_SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+
+    let mut file = Cursor::new(Vec::new());
+    let mut writer = WavWriter::new(&mut file, spec).map_err(|err| err.to_string())?;
+
+    // the wrap-around samples aren't part of the frames
+    for &sample in wavetable.iter().flat_map(|frame| &frame[..WAVE_FRAME_LEN]) {
+        writer.write_sample(sample).map_err(|err| err.to_string())?;
+    }
+
+    writer.finalize().map_err(|err| err.to_string())?;
+
+    // `hound` only writes the chunks it knows about, and writes the format chunk first
+    let mut file = file.into_inner();
+    let text = format!("<!>{WAVE_FRAME_LEN} 00000000 wavetable (Krynth)");
+    insert_riff_chunk(&mut file, FORMAT_CHUNK, SERUM_CHUNK, text.as_bytes())?;
+
+    write(path, file).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use import::riff_chunks;
+    use std::fs::{read, remove_file};
+
+    #[test]
+    fn round_trips() {
+        let wavetable = factory_wavetable("Harmonic Sweep").unwrap();
+        let path = std::env::temp_dir().join("krynth_export_round_trip.wav");
+
+        write_wav_wavetable(&path, &wavetable).unwrap();
+        let file = read(&path).unwrap();
+        let read_back = read_wavetable(&path, ImportChannel::Mix);
+        remove_file(&path).unwrap();
+
+        // the frame length is given to other synths, right after the format chunk
+        let mut chunks = riff_chunks(&file).map(|(id, data, _)| (id, data));
+        assert_eq!(chunks.next().map(|(id, _)| id), Some(FORMAT_CHUNK));
+        let (id, text) = chunks.next().unwrap();
+        assert_eq!(id, SERUM_CHUNK);
+        assert!(text.starts_with(format!("<!>{WAVE_FRAME_LEN} ").as_bytes()));
+
+        let read_back = read_back.unwrap();
+        assert_eq!(read_back.len(), FRAMES_PER_WT);
+        assert!(read_back == wavetable, "the wavetable changed on the way");
+    }
+}
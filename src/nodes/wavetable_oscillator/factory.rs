@@ -0,0 +1,126 @@
+use super::*;
+use realfft::{num_complex::Complex32, RealFftPlanner};
+use std::f32::consts::PI;
+
+/// Wavetables built in code, available without any file on disk
+pub(super) const FACTORY_TABLES: [&str; 3] = ["Basic Shapes", "PWM", "Harmonic Sweep"];
+
+/// Narrowest pulse of the PWM table, as a fraction of the period
+const MIN_PULSE_WIDTH: f32 = 0.01;
+
+/// Sine, triangle, saw and square, the basic shapes morph through them in that order
+const BASIC_SHAPES: [fn(usize) -> f32; 4] = [sine, triangle, saw, square];
+
+/// The factory wavetable called `name`, if there is one
+pub(super) fn factory_wavetable(name: &str) -> Option<Vec<WaveFrame>> {
+    let harmonic: fn(usize, f32) -> Complex32 = match name {
+        "Basic Shapes" => basic_shapes,
+        "PWM" => pwm,
+        "Harmonic Sweep" => harmonic_sweep,
+        _ => return None,
+    };
+
+    Some(wavetable_from_harmonics(harmonic))
+}
+
+/// Builds every frame from its spectrum, `harmonic(k, morph)` being the `k`th harmonic
+/// of the frame at `morph`, from 0 (first frame) to 1 (last frame). Frames are
+/// normalized to a peak of 1, and are bandlimited, as they never go past nyquist.
+fn wavetable_from_harmonics(harmonic: fn(usize, f32) -> Complex32) -> Vec<WaveFrame> {
+    let mut c2r = RealFftPlanner::<f32>::new();
+    let fft = c2r.plan_fft_inverse(WAVE_FRAME_LEN);
+
+    let mut spectrum = fft.make_input_vec();
+    let mut scratch = fft.make_scratch_vec();
+
+    (0..FRAMES_PER_WT)
+        .map(|frame| {
+            let morph = frame as f32 / (FRAMES_PER_WT - 1) as f32;
+
+            // no DC, and nothing at nyquist, where the phase of a harmonic is lost
+            spectrum.fill(Complex32::new(0., 0.));
+            for (k, bin) in spectrum.iter_mut().enumerate().take(WAVE_FRAME_LEN / 2).skip(1) {
+                *bin = harmonic(k, morph);
+            }
+
+            let mut frame = [0.; WAVE_FRAME_LEN + 1];
+            let (wrap_around, window) = frame.split_last_mut().unwrap();
+
+            fft.process_with_scratch(&mut spectrum, window, &mut scratch)
+                .expect("wrong buffer sizes");
+
+            let peak = window.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+            if peak > 0. {
+                window.iter_mut().for_each(|sample| *sample /= peak);
+            }
+
+            *wrap_around = window[0];
+            frame
+        })
+        .collect()
+}
+
+/// Bin of a harmonic of the given amplitude, in sine phase
+fn sine_bin(amplitude: f32) -> Complex32 {
+    Complex32::new(0., -amplitude)
+}
+
+/// Bin of a harmonic of the given amplitude, in cosine phase
+fn cosine_bin(amplitude: f32) -> Complex32 {
+    Complex32::new(amplitude, 0.)
+}
+
+fn basic_shapes(k: usize, morph: f32) -> Complex32 {
+    let position = morph * (BASIC_SHAPES.len() - 1) as f32;
+    let shape = (position as usize).min(BASIC_SHAPES.len() - 2);
+    let fade = position - shape as f32;
+
+    let (from, to) = (BASIC_SHAPES[shape](k), BASIC_SHAPES[shape + 1](k));
+    sine_bin(from + (to - from) * fade)
+}
+
+fn sine(k: usize) -> f32 {
+    if k == 1 {
+        1.
+    } else {
+        0.
+    }
+}
+
+fn triangle(k: usize) -> f32 {
+    let sign = match k % 4 {
+        1 => 1.,
+        3 => -1.,
+        _ => return 0.,
+    };
+
+    sign * 8. / (PI * k as f32).powi(2)
+}
+
+fn saw(k: usize) -> f32 {
+    let sign = if k % 2 == 1 { 1. } else { -1. };
+    sign * 2. / (PI * k as f32)
+}
+
+fn square(k: usize) -> f32 {
+    if k % 2 == 1 {
+        4. / (PI * k as f32)
+    } else {
+        0.
+    }
+}
+
+/// A pulse, narrowing from a square wave down to `MIN_PULSE_WIDTH`
+fn pwm(k: usize, morph: f32) -> Complex32 {
+    let width = 0.5 + (MIN_PULSE_WIDTH - 0.5) * morph;
+    cosine_bin(4. / (PI * k as f32) * (PI * k as f32 * width).sin())
+}
+
+/// Harmonics of a saw wave, from the fundamental alone to all of them, added one by one,
+/// with their number rising exponentially, the last one added fades in
+fn harmonic_sweep(k: usize, morph: f32) -> Complex32 {
+    let num_harmonics = ((WAVE_FRAME_LEN / 2 - 1) as f32).powf(morph);
+    let fade = (num_harmonics - (k - 1) as f32).clamp(0., 1.);
+
+    sine_bin(fade / k as f32)
+}
@@ -2,60 +2,86 @@ use super::*;
 use plot::*;
 use std::{fs::read_dir, ops::Deref, sync::OnceLock};
 
-use wavetable::write_wavetable_from_file;
-
 static WT_LIST: OnceLock<Vec<String>> = OnceLock::new();
 
-const WAVETABLE_FOLDER_PATH: &str =
-    "C:\\Users\\etulyon1\\Documents\\Coding\\Krynth\\wavetables";
-
 impl SeenthNode for WTOscParams {
     fn type_name(&self) -> &'static str {
         "Oscillator"
     }
 
-    fn ui(&self, ui: &mut Ui, setter: &ParamSetter) -> Response {
+    fn ui(
+        &self,
+        ui: &mut Ui,
+        setter: &ParamSetter,
+        midi_learn: &MidiLearn,
+        executor: &BackgroundExecutor,
+    ) -> Response {
+        self.free_old_tables();
+        self.update_wt_name();
+
         ui.horizontal(|ui| {
             ui.vertical(|ui| {
-                ui.add(ParamWidget::new(
-                    Knob::new().radius(40.),
-                    ParamHandle::from((self.level.deref(), setter)),
-                ));
+                ui.horizontal(|ui| {
+                    midi_learn.context_menu(
+                        ui.add(ParamWidget::new(
+                            Knob::new().radius(40.),
+                            ParamHandle::from((self.level.deref(), setter)),
+                        )),
+                        "level",
+                    );
+
+                    midi_learn.context_menu(
+                        ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
+                            (&self.release, setter).into(),
+                        )),
+                        "release",
+                    );
+                });
 
                 ui.horizontal(|ui| {
-                    ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
-                        (self.num_unison_voices.deref(), setter).into(),
-                    ));
+                    midi_learn.context_menu(
+                        ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
+                            (self.num_unison_voices.deref(), setter).into(),
+                        )),
+                        "unison",
+                    );
 
-                    ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
-                        (self.pan.as_ref().deref(), setter).into(),
-                    ));
+                    midi_learn.context_menu(
+                        ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
+                            (self.pan.as_ref().deref(), setter).into(),
+                        )),
+                        "pan",
+                    );
                 });
 
                 ui.horizontal(|ui| {
-                    ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
-                        (self.detune.deref(), setter).into(),
-                    ));
+                    midi_learn.context_menu(
+                        ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
+                            (self.detune.deref(), setter).into(),
+                        )),
+                        "detune",
+                    );
 
-                    ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
-                        (self.detune_range.deref(), setter).into(),
-                    ));
+                    midi_learn.context_menu(
+                        ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
+                            (self.detune_range.deref(), setter).into(),
+                        )),
+                        "det_range",
+                    );
                 });
             });
 
             ui.vertical_centered_justified(|ui| {
-                let mut current_name_ref = self.wt_name.borrow_mut();
+                let current_name_ref = self.wt_name.borrow();
 
                 let wt_list = WT_LIST.get_or_init(|| {
+                    // the factory tables still show up without a wavetable folder
                     read_dir(WAVETABLE_FOLDER_PATH)
-                        .unwrap()
-                        .map(|dir| {
-                            dir.unwrap()
-                                .file_name()
-                                .to_string_lossy()
-                                .trim_end_matches(".WAV")
-                                .into()
-                        })
+                        .into_iter()
+                        .flatten()
+                        .filter_map(Result::ok)
+                        .filter(|dir| is_wavetable_file(dir.file_name()))
+                        .map(|dir| dir.file_name().to_string_lossy().into())
                         .collect::<Vec<_>>()
                 });
 
@@ -63,29 +89,56 @@ impl SeenthNode for WTOscParams {
                     .width(ui.available_width())
                     .selected_text(current_name_ref.deref())
                     .show_ui(ui, |ui| {
-                        for name in wt_list.iter() {
+                        let names = FACTORY_TABLES
+                            .into_iter()
+                            .chain(wt_list.iter().map(String::as_str));
+
+                        for name in names {
                             if ui
                                 .selectable_label(name == current_name_ref.as_str(), name)
                                 .clicked()
                             {
-                                *current_name_ref = name.clone();
-                                write_wavetable_from_file(
-                                    format!("{WAVETABLE_FOLDER_PATH}\\{name}.WAV"),
-                                    self.wavetable
-                                        .borrow_mut()
-                                        .as_mut_slice()
-                                        .try_into()
-                                        .unwrap(),
-                                );
+                                self.load_wavetable(name.into(), executor);
+                            }
+                        }
+                    });
+
+                let current_channel = *self.import_channel.borrow();
+                let mut new_channel = None;
+
+                ComboBox::from_label("Import Channel")
+                    .selected_text(ImportChannel::variants()[current_channel.to_index()])
+                    .show_ui(ui, |ui| {
+                        for (i, &name) in ImportChannel::variants().iter().enumerate() {
+                            if ui
+                                .selectable_label(i == current_channel.to_index(), name)
+                                .clicked()
+                            {
+                                new_channel = Some(ImportChannel::from_index(i));
                             }
                         }
                     });
 
+                if let Some(channel) = new_channel.filter(|&channel| channel != current_channel) {
+                    *self.import_channel.borrow_mut() = channel;
+                    // the current wavetable might come from another channel now
+                    self.load_wavetable(current_name_ref.clone(), executor);
+                }
+
+                ui.horizontal(|ui| {
+                    let mut export_path = self.export_path.borrow_mut();
+                    ui.text_edit_singleline(&mut *export_path);
+
+                    if ui.button("Export").clicked() && !export_path.is_empty() {
+                        self.export_wavetable(export_path.clone(), executor);
+                    }
+                });
+
                 ui.horizontal_centered(|ui| {
-                    let wavetable = self.wavetable.borrow();
+                    let wavetable = self.wavetable.lock();
 
                     let points = PlotPoints::from_ys_f32(
-                        wavetable[self.frame.unmodulated_plain_value() as usize].as_slice(),
+                        wavetable.frames[self.frame.unmodulated_plain_value() as usize].as_slice(),
                     );
 
                     plain_plot(
@@ -95,15 +148,22 @@ impl SeenthNode for WTOscParams {
                     )
                     .show(ui, |plot_ui| plot_ui.line(Line::new(points).fill(0.)));
 
-                    ui.add(ParamWidget::<VSlider, ParamHandle<_>>::default(
-                        (self.frame.deref(), setter).into(),
-                    ));
+                    midi_learn.context_menu(
+                        ui.add(ParamWidget::<VSlider, ParamHandle<_>>::default(
+                            (self.frame.deref(), setter).into(),
+                        )),
+                        "frame",
+                    );
                 });
             })
         })
         .response
     }
 
+    fn modulation_targets(&self) -> &'static [&'static str] {
+        &MOD_TARGETS
+    }
+
     fn processor_node(self: Arc<Self>) -> Box<ProcessNode> {
         Box::new(self.oscillator())
     }
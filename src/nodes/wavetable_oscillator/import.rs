@@ -0,0 +1,453 @@
+mod vital;
+
+use super::*;
+use hound::{SampleFormat, WavReader};
+use realfft::{num_complex::Complex32, RealFftPlanner};
+use std::{fs::read, io::Cursor, path::Path};
+
+/// The RIFF header is a chunk header, then the form type ("WAVE")
+const RIFF_HEADER_LEN: usize = 12;
+/// Chunks start with their ID, then (32-bit, little endian) size
+const CHUNK_HEADER_LEN: usize = 8;
+
+/// Chunk holding the sample format, always the first one
+pub(super) const FORMAT_CHUNK: [u8; 4] = *b"fmt ";
+/// Chunk written by Serum (and most wavetable editors since), holding text
+/// starting with `<!>` followed by the frame length, e. g. `<!>2048 ...`
+pub(super) const SERUM_CHUNK: [u8; 4] = *b"clm ";
+/// Chunk written by Surge, holding a (little endian) 32-bit version, then frame length
+const SURGE_CHUNK: [u8; 4] = *b"srge";
+
+/// Tag starting the header of .wt files
+const WT_TAG: [u8; 4] = *b"vawt";
+/// Tag, frame length (32-bit), number of frames (16-bit), then flags (16-bit), little endian
+const WT_HEADER_LEN: usize = 12;
+/// The file holds a one-shot sample, not a wavetable
+const WT_IS_SAMPLE: u16 = 0x1;
+/// Samples are 16-bit integers, otherwise 32-bit floats
+const WT_INT16: u16 = 0x4;
+/// 16-bit samples use their whole range, otherwise, they peak at 2^14
+const WT_INT16_FULL_RANGE: u16 = 0x8;
+
+/// Extensions (lowercase) of the files `read_wavetable` can read
+const EXTENSIONS: [&str; 3] = ["wav", "wt", "vital"];
+
+/// Which channel of multichannel files wavetables are read from
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportChannel {
+    /// the average of every channel
+    #[name = "Mix"]
+    Mix,
+    #[name = "Left"]
+    Left,
+    #[name = "Right"]
+    Right,
+}
+
+/// Reads the wavetable in the file at `path`, in any of the supported formats, told
+/// apart by their extension, `channel` is only used for multichannel formats
+pub(super) fn read_wavetable(
+    path: impl AsRef<Path>,
+    channel: ImportChannel,
+) -> Result<Vec<WaveFrame>, String> {
+    let path = path.as_ref();
+
+    match extension(path).as_deref() {
+        Some("wav") => read_wav_wavetable(path, channel),
+        Some("wt") => read_wt_wavetable(path),
+        Some("vital") => vital::read_vital_wavetable(path),
+        _ => Err("unsupported file format".into()),
+    }
+}
+
+/// Whether `read_wavetable` can read the file at `path`, judging by its extension
+pub(super) fn is_wavetable_file(path: impl AsRef<Path>) -> bool {
+    extension(path.as_ref()).map_or(false, |extension| EXTENSIONS.contains(&extension.as_str()))
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+}
+
+/// Reads the wavetable in the WAV file at `path`, whatever its sample format
+/// and number of channels, see `wavetable_from_samples`
+fn read_wav_wavetable(
+    path: impl AsRef<Path>,
+    channel: ImportChannel,
+) -> Result<Vec<WaveFrame>, String> {
+    let file = read(path).map_err(|err| err.to_string())?;
+
+    let reader = WavReader::new(Cursor::new(file.as_slice())).map_err(|err| err.to_string())?;
+    let spec = reader.spec();
+
+    let samples = match spec.sample_format {
+        SampleFormat::Float => reader.into_samples::<f32>().collect::<Result<Vec<_>, _>>(),
+        SampleFormat::Int => {
+            // integer samples are sign extended to 32 bits, whatever their size
+            let scale = ((1u32 << (spec.bits_per_sample - 1)) as f32).recip();
+            reader
+                .into_samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 * scale))
+                .collect()
+        }
+    }
+    .map_err(|err| err.to_string())?;
+
+    let samples = select_channel(&samples, spec.channels as usize, channel)?;
+
+    // without any hint of the frame length, assume it's ours
+    let frame_len = frame_len_hint(&file).unwrap_or(WAVE_FRAME_LEN.min(samples.len()));
+
+    wavetable_from_samples(&samples, frame_len)
+}
+
+/// Reads the wavetable in the .wt (Surge, Bitwig) file at `path`
+fn read_wt_wavetable(path: impl AsRef<Path>) -> Result<Vec<WaveFrame>, String> {
+    let file = read(path).map_err(|err| err.to_string())?;
+
+    if file.len() < WT_HEADER_LEN {
+        return Err("the file is too short to hold a header".into());
+    }
+
+    let (header, data) = file.split_at(WT_HEADER_LEN);
+
+    if header[..4] != WT_TAG {
+        return Err("not a .wt file".into());
+    }
+
+    let frame_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let num_frames = u16::from_le_bytes(header[8..10].try_into().unwrap()) as usize;
+    let flags = u16::from_le_bytes(header[10..12].try_into().unwrap());
+
+    if flags & WT_IS_SAMPLE != 0 {
+        return Err("the file holds a sample, not a wavetable".into());
+    }
+
+    let sample_size = if flags & WT_INT16 != 0 { 2 } else { 4 };
+
+    // anything after the samples (such as Surge's metadata) is ignored
+    let data = data
+        .get(..frame_len * num_frames * sample_size)
+        .ok_or("the file is shorter than its header says")?;
+
+    let samples = if flags & WT_INT16 != 0 {
+        let scale = if flags & WT_INT16_FULL_RANGE != 0 {
+            1. / (1 << 15) as f32
+        } else {
+            1. / (1 << 14) as f32
+        };
+
+        data.array_chunks()
+            .map(|&bytes| i16::from_le_bytes(bytes) as f32 * scale)
+            .collect::<Vec<_>>()
+    } else {
+        data.array_chunks().map(|&bytes| f32::from_le_bytes(bytes)).collect()
+    };
+
+    wavetable_from_samples(&samples, frame_len)
+}
+
+/// Frame length given by the metadata of a WAV file, if any
+fn frame_len_hint(file: &[u8]) -> Option<usize> {
+    riff_chunks(file).find_map(|(id, data, _)| match id {
+        SERUM_CHUNK => {
+            let text = data.strip_prefix(b"<!>")?;
+            let digits = text.iter().take_while(|byte| byte.is_ascii_digit()).count();
+
+            std::str::from_utf8(&text[..digits]).ok()?.parse().ok()
+        }
+        SURGE_CHUNK => {
+            let frame_len = data.get(4..8)?.try_into().ok()?;
+            usize::try_from(i32::from_le_bytes(frame_len)).ok()
+        }
+        _ => None,
+    })
+}
+
+/// IDs and contents of the top-level chunks of a RIFF (WAV) file, and the offsets they end at
+pub(super) fn riff_chunks(file: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8], usize)> {
+    let mut offset = RIFF_HEADER_LEN;
+
+    std::iter::from_fn(move || {
+        let chunk = file.get(offset..)?;
+        let id = chunk.get(..4)?.try_into().ok()?;
+        let size = u32::from_le_bytes(chunk.get(4..CHUNK_HEADER_LEN)?.try_into().ok()?) as usize;
+
+        // chunks are padded to an even size, the last one might be truncated
+        let data = &chunk[CHUNK_HEADER_LEN..];
+        let data = &data[..size.min(data.len())];
+        offset = (offset + CHUNK_HEADER_LEN + size + size % 2).min(file.len());
+
+        Some((id, data, offset))
+    })
+}
+
+/// Chunk `id` holding `data`, header and padding included
+fn riff_chunk(id: [u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = id.to_vec();
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    chunk.resize(CHUNK_HEADER_LEN + data.len() + data.len() % 2, 0);
+    chunk
+}
+
+/// Inserts chunk `id` holding `data` in a RIFF (WAV) file, right after the chunk `after`
+pub(super) fn insert_riff_chunk(
+    file: &mut Vec<u8>,
+    after: [u8; 4],
+    id: [u8; 4],
+    data: &[u8],
+) -> Result<(), String> {
+    let offset = riff_chunks(file)
+        .find_map(|(chunk_id, _, end)| (chunk_id == after).then_some(end))
+        .ok_or_else(|| format!("no {:?} chunk", String::from_utf8_lossy(&after)))?;
+    let chunk = riff_chunk(id, data);
+
+    // the RIFF size counts what follows it
+    let riff_size = (file.len() + chunk.len() - CHUNK_HEADER_LEN) as u32;
+    file[4..CHUNK_HEADER_LEN].copy_from_slice(&riff_size.to_le_bytes());
+    file.splice(offset..offset, chunk);
+
+    Ok(())
+}
+
+/// Deinterleaves `channel` out of `samples`
+fn select_channel(
+    samples: &[f32],
+    channels: usize,
+    channel: ImportChannel,
+) -> Result<Vec<f32>, String> {
+    if channels == 0 {
+        return Err("the file has no channels".into());
+    }
+
+    let frames = samples.chunks_exact(channels);
+
+    Ok(match channel {
+        ImportChannel::Mix => frames
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect(),
+        ImportChannel::Left => frames.map(|frame| frame[0]).collect(),
+        ImportChannel::Right => frames.map(|frame| frame[1.min(channels - 1)]).collect(),
+    })
+}
+
+/// Splits `samples` into frames of `frame_len` samples, resamples them to `WAVE_FRAME_LEN`
+/// samples, then interpolates between them to get `FRAMES_PER_WT` frames. Samples
+/// at the end that don't fill a whole frame are ignored.
+pub(super) fn wavetable_from_samples(
+    samples: &[f32],
+    frame_len: usize,
+) -> Result<Vec<WaveFrame>, String> {
+    if frame_len == 0 || samples.len() < frame_len {
+        return Err("the file doesn't hold a single frame".into());
+    }
+
+    Ok(interpolate_frames(resample_frames(samples, frame_len)))
+}
+
+/// Resamples every frame to `WAVE_FRAME_LEN` samples, by zero-padding (or truncating) its spectrum
+fn resample_frames(samples: &[f32], frame_len: usize) -> Vec<WaveFrame> {
+    let mut planner = RealFftPlanner::<f32>::new();
+    let r2c = planner.plan_fft_forward(frame_len);
+    let c2r = planner.plan_fft_inverse(WAVE_FRAME_LEN);
+
+    let mut input = r2c.make_input_vec();
+    let mut spectrum = r2c.make_output_vec();
+    let mut resampled = c2r.make_input_vec();
+    let mut r2c_scratch = r2c.make_scratch_vec();
+    let mut c2r_scratch = c2r.make_scratch_vec();
+
+    let bins = spectrum.len().min(resampled.len());
+
+    samples
+        .chunks_exact(frame_len)
+        .map(|samples| {
+            let mut frame = [0.; WAVE_FRAME_LEN + 1];
+            let (wrap_around, window) = frame.split_last_mut().unwrap();
+
+            if frame_len == WAVE_FRAME_LEN {
+                window.copy_from_slice(samples);
+            } else {
+                input.copy_from_slice(samples);
+                r2c.process_with_scratch(&mut input, &mut spectrum, &mut r2c_scratch)
+                    .expect("wrong buffer sizes");
+
+                resampled.fill(Complex32::new(0., 0.));
+                resampled[..bins].copy_from_slice(&spectrum[..bins]);
+
+                // the nyquist bin of an even length frame holds half of what a regular
+                // bin does, and, when shrinking a frame, ours has to stay empty
+                if frame_len < WAVE_FRAME_LEN && frame_len % 2 == 0 {
+                    resampled[bins - 1] *= 0.5;
+                } else if let Some(nyquist) = resampled.last_mut() {
+                    *nyquist = Complex32::new(0., 0.);
+                }
+
+                c2r.process_with_scratch(&mut resampled, window, &mut c2r_scratch)
+                    .expect("wrong buffer sizes");
+
+                window.iter_mut().for_each(|sample| *sample /= frame_len as f32);
+            }
+
+            *wrap_around = window[0];
+            frame
+        })
+        .collect()
+}
+
+/// Stretches (or squeezes) `frames` to `FRAMES_PER_WT` frames, linearly interpolating between them
+fn interpolate_frames(frames: Vec<WaveFrame>) -> Vec<WaveFrame> {
+    if frames.len() == FRAMES_PER_WT {
+        return frames;
+    }
+
+    let last = frames.len() - 1;
+    let step = last as f32 / (FRAMES_PER_WT - 1) as f32;
+
+    (0..FRAMES_PER_WT)
+        .map(|i| {
+            let position = i as f32 * step;
+            let idx = (position as usize).min(last);
+            let fract = position - idx as f32;
+
+            let mut frame = frames[idx];
+            let next = &frames[(idx + 1).min(last)];
+
+            for (sample, next) in frame.iter_mut().zip(next) {
+                *sample += (next - *sample) * fract;
+            }
+
+            frame
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    const EPSILON: f32 = 1e-4;
+
+    /// `num_frames` periods of a sine, `frame_len` samples each
+    fn sines(frame_len: usize, num_frames: usize) -> Vec<f32> {
+        (0..frame_len * num_frames)
+            .map(|i| (TAU * i as f32 / frame_len as f32).sin())
+            .collect()
+    }
+
+    /// Checks every sample of `frame` against `expected`, given their index in the frame
+    pub(super) fn assert_frame_eq(frame: &WaveFrame, expected: impl Fn(usize) -> f32) {
+        for (i, &sample) in frame.iter().enumerate() {
+            let expected = expected(i % WAVE_FRAME_LEN);
+            assert!(
+                (sample - expected).abs() < EPSILON,
+                "sample {i} is {sample}, expected {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn resamples_short_frames() {
+        let wavetable = wavetable_from_samples(&sines(1024, 2), 1024).unwrap();
+
+        assert_eq!(wavetable.len(), FRAMES_PER_WT);
+        for frame in wavetable.iter() {
+            assert_frame_eq(frame, |i| (TAU * i as f32 / WAVE_FRAME_LEN as f32).sin());
+        }
+    }
+
+    #[test]
+    fn resamples_odd_length_frames() {
+        let wavetable = wavetable_from_samples(&sines(2047, 1), 2047).unwrap();
+
+        for frame in [wavetable.first().unwrap(), wavetable.last().unwrap()] {
+            assert_frame_eq(frame, |i| (TAU * i as f32 / WAVE_FRAME_LEN as f32).sin());
+        }
+    }
+
+    #[test]
+    fn interpolates_between_frames() {
+        // a sine, then silence
+        let mut samples = sines(WAVE_FRAME_LEN, 1);
+        samples.resize(WAVE_FRAME_LEN * 2, 0.);
+
+        let wavetable = wavetable_from_samples(&samples, WAVE_FRAME_LEN).unwrap();
+
+        assert_eq!(wavetable.len(), FRAMES_PER_WT);
+        for (n, frame) in wavetable.iter().enumerate() {
+            let gain = 1. - n as f32 / (FRAMES_PER_WT - 1) as f32;
+            assert_frame_eq(frame, |i| gain * (TAU * i as f32 / WAVE_FRAME_LEN as f32).sin());
+        }
+    }
+
+    #[test]
+    fn rejects_missing_frames() {
+        assert!(wavetable_from_samples(&[0.; 100], WAVE_FRAME_LEN).is_err());
+        assert!(wavetable_from_samples(&[0.; 100], 0).is_err());
+    }
+
+    /// A RIFF file holding `chunks`
+    fn riff(chunks: &[([u8; 4], &[u8])]) -> Vec<u8> {
+        let mut body = b"WAVE".to_vec();
+
+        for &(id, data) in chunks {
+            body.extend(riff_chunk(id, data));
+        }
+
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        file.extend(body);
+        file
+    }
+
+    /// Contents of a Surge chunk, version, then frame length
+    fn surge_chunk(frame_len: i32) -> Vec<u8> {
+        [1i32, frame_len].into_iter().flat_map(i32::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn finds_serum_frame_len() {
+        let file = riff(&[(SERUM_CHUNK, b"<!>1024 01000000 wavetable (www.xferrecords.com)")]);
+        assert_eq!(frame_len_hint(&file), Some(1024));
+    }
+
+    #[test]
+    fn finds_surge_frame_len() {
+        let file = riff(&[(SURGE_CHUNK, &surge_chunk(512))]);
+        assert_eq!(frame_len_hint(&file), Some(512));
+    }
+
+    #[test]
+    fn skips_padding_of_odd_sized_chunks() {
+        let file = riff(&[
+            (*b"fmt ", &[0; 3]),
+            (*b"LIST", &[1; 5]),
+            (SURGE_CHUNK, &surge_chunk(256)),
+        ]);
+        assert_eq!(frame_len_hint(&file), Some(256));
+    }
+
+    #[test]
+    fn reads_truncated_chunks() {
+        // the file ends before the chunk does
+        let mut file = riff(&[(SERUM_CHUNK, b"<!>256 ")]);
+        let size_offset = file.len() - 7 - 1 - 4;
+        file[size_offset..size_offset + 4].copy_from_slice(&100u32.to_le_bytes());
+
+        assert_eq!(frame_len_hint(&file), Some(256));
+
+        // and before its header does
+        let file = riff(&[(*b"data", &[0; 4])]);
+        assert_eq!(frame_len_hint(&file[..file.len() - 6]), None);
+    }
+
+    #[test]
+    fn ignores_files_without_hints() {
+        assert_eq!(frame_len_hint(&riff(&[(*b"data", &[0; 8])])), None);
+        assert_eq!(frame_len_hint(&riff(&[(SERUM_CHUNK, b"2048")])), None);
+        assert_eq!(frame_len_hint(b"RIFF"), None);
+    }
+}
@@ -0,0 +1,171 @@
+use super::*;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use std::fs::read_to_string;
+
+/// Type of the wavetable components made of (keyframed) waveforms
+const WAVE_SOURCE: &str = "Wave Source";
+
+// only the parts of the preset we're interested in, everything else is skipped
+
+#[derive(Deserialize)]
+struct VitalPreset {
+    settings: VitalSettings,
+}
+
+#[derive(Deserialize)]
+struct VitalSettings {
+    #[serde(default)]
+    wavetables: Vec<VitalWavetable>,
+}
+
+#[derive(Deserialize)]
+struct VitalWavetable {
+    #[serde(default)]
+    groups: Vec<VitalGroup>,
+}
+
+#[derive(Deserialize)]
+struct VitalGroup {
+    #[serde(default)]
+    components: Vec<VitalComponent>,
+}
+
+#[derive(Deserialize)]
+struct VitalComponent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    keyframes: Vec<VitalKeyframe>,
+}
+
+#[derive(Deserialize)]
+struct VitalKeyframe {
+    position: f32,
+    /// base64 encoded (little endian) 32-bit floats
+    wave_data: Option<String>,
+}
+
+/// Reads the wavetable of the first oscillator of the Vital preset at `path` that plays
+/// waveforms, other sources (audio files, lines...) aren't supported. Frames are
+/// linearly interpolated between keyframes, whatever the preset's interpolation style.
+pub(super) fn read_vital_wavetable(path: impl AsRef<Path>) -> Result<Vec<WaveFrame>, String> {
+    let file = read_to_string(path).map_err(|err| err.to_string())?;
+    wavetable_from_preset(&file)
+}
+
+/// Vital's wavetables have a frame at every position from 0 to 256, each of our frames
+/// is the one at its own position, Vital's last frame is dropped
+fn wavetable_from_preset(preset: &str) -> Result<Vec<WaveFrame>, String> {
+    let preset: VitalPreset = serde_json::from_str(preset).map_err(|err| err.to_string())?;
+
+    let component = preset
+        .settings
+        .wavetables
+        .iter()
+        .flat_map(|wavetable| &wavetable.groups)
+        .flat_map(|group| &group.components)
+        .find(|component| component.kind == WAVE_SOURCE)
+        .ok_or("the preset has no wavetable made of waveforms")?;
+
+    let mut keyframes = component
+        .keyframes
+        .iter()
+        .map(|keyframe| {
+            let wave_data = keyframe.wave_data.as_deref().ok_or("a keyframe has no waveform")?;
+            Ok((keyframe.position, decode_frame(wave_data)?))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if keyframes.is_empty() {
+        return Err("the wavetable has no keyframes".into());
+    }
+
+    keyframes.sort_by(|(p1, _), (p2, _)| p1.total_cmp(p2));
+    let last = keyframes.len() - 1;
+
+    Ok((0..FRAMES_PER_WT)
+        .map(|i| {
+            let position = i as f32;
+
+            // frames before the first, or after the last, keyframe hold its waveform
+            let next = keyframes.partition_point(|&(keyframe, _)| keyframe <= position);
+            let (from_position, from) = &keyframes[next.saturating_sub(1)];
+            let (to_position, to) = &keyframes[next.min(last)];
+
+            let fade = if to_position > from_position {
+                (position - from_position) / (to_position - from_position)
+            } else {
+                0.
+            };
+
+            let mut frame = *from;
+            for (sample, to) in frame.iter_mut().zip(to) {
+                *sample += (to - *sample) * fade;
+            }
+
+            frame
+        })
+        .collect())
+}
+
+fn decode_frame(wave_data: &str) -> Result<WaveFrame, String> {
+    let bytes = STANDARD.decode(wave_data).map_err(|err| err.to_string())?;
+
+    if bytes.len() != WAVE_FRAME_LEN * 4 {
+        return Err(format!("expected waveforms of {WAVE_FRAME_LEN} samples"));
+    }
+
+    let mut frame = [0.; WAVE_FRAME_LEN + 1];
+    let (wrap_around, window) = frame.split_last_mut().unwrap();
+
+    for (sample, &bytes) in window.iter_mut().zip(bytes.array_chunks()) {
+        *sample = f32::from_le_bytes(bytes);
+    }
+
+    *wrap_around = window[0];
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::assert_frame_eq;
+
+    /// base64 of a waveform where every sample is `value`
+    fn encode_frame(value: f32) -> String {
+        STANDARD.encode(value.to_le_bytes().repeat(WAVE_FRAME_LEN))
+    }
+
+    #[test]
+    fn interpolates_between_keyframes() {
+        let preset = format!(
+            r#"{{"settings": {{"wavetables": [{{"groups": [{{"components": [{{
+                "type": "Wave Source",
+                "keyframes": [
+                    {{"position": 256, "wave_data": "{}"}},
+                    {{"position": 0, "wave_data": "{}"}}
+                ]
+            }}]}}]}}]}}}}"#,
+            encode_frame(-1.),
+            encode_frame(0.5),
+        );
+
+        let wavetable = wavetable_from_preset(&preset).unwrap();
+
+        assert_eq!(wavetable.len(), FRAMES_PER_WT);
+        assert_frame_eq(&wavetable[0], |_| 0.5);
+        assert_frame_eq(&wavetable[128], |_| -0.25);
+        // the frame at 256 is dropped
+        assert_frame_eq(&wavetable[255], |_| 0.5 - 1.5 * 255. / 256.);
+    }
+
+    #[test]
+    fn rejects_presets_without_waveforms() {
+        let preset = r#"{"settings": {"wavetables": [{"groups": [{"components": [
+            {"type": "Line Source", "keyframes": [{"position": 0}]}
+        ]}]}]}}"#;
+
+        assert!(wavetable_from_preset(preset).is_err());
+    }
+}
@@ -1,98 +1,223 @@
-use hound::{SampleFormat, WavReader};
-use plugin_util::dsp::lerp_table;
 use realfft::num_complex::Complex32;
-use std::{path::Path, simd::{usizex2, SimdUint}};
+use parking_lot::Mutex;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    simd::{LaneCount, Mask, Simd, SimdFloat, SimdPartialOrd, SimdUint, SupportedLaneCount},
+    sync::{OnceLock, Weak},
+};
 
 use super::*;
 
-pub const fn empty_wavetable() -> WaveTable {
-    [[0.; WAVE_FRAME_LEN + 1]; FRAMES_PER_WT]
-}
-
 pub const PHASE_RANGE: f32 = WAVE_FRAME_LEN as f32;
 const NUM_WAVETABLES: usize = WAVE_FRAME_LEN.ilog2() as usize + 1;
 const SPECTRUM_SIZE: usize = WAVE_FRAME_LEN / 2 + 1;
 
 type Spectrum = [Complex32; SPECTRUM_SIZE];
 
-pub(super) fn write_wavetable_from_file(path: impl AsRef<Path>, wt: &mut WaveTable) {
-    let reader = WavReader::open(path).unwrap();
-    let spec = reader.spec();
+/// Samples per period of the highest harmonic of a level, a level's frames are
+/// this many times longer than the shortest that could hold its harmonics, the
+/// more, the less error linear interpolation makes, up to `WAVE_FRAME_LEN`
+const SAMPLES_PER_HARMONIC: usize = 8;
+const MIN_LEVEL_LEN: usize = 16;
+
+/// Length of the frames of every level, without the wrap-around sample
+const LEVEL_LENS: [usize; NUM_WAVETABLES] = level_lens();
+/// Where every level starts in `BandlimitedWaveTables`, the last element is where the last ends
+const LEVEL_OFFSETS: [usize; NUM_WAVETABLES + 1] = level_offsets();
+
+const fn level_lens() -> [usize; NUM_WAVETABLES] {
+    let mut lens = [MIN_LEVEL_LEN; NUM_WAVETABLES];
+
+    // the first level is silent, it can stay as short as possible
+    let mut level = 1;
+    while level < NUM_WAVETABLES {
+        let len = (1 << (level - 1)) * SAMPLES_PER_HARMONIC;
+
+        lens[level] = if len < MIN_LEVEL_LEN {
+            MIN_LEVEL_LEN
+        } else if len > WAVE_FRAME_LEN {
+            WAVE_FRAME_LEN
+        } else {
+            len
+        };
+
+        level += 1;
+    }
+
+    lens
+}
+
+const fn level_offsets() -> [usize; NUM_WAVETABLES + 1] {
+    let mut offsets = [0; NUM_WAVETABLES + 1];
+
+    let mut level = 0;
+    while level < NUM_WAVETABLES {
+        // every frame has a wrap-around sample, a copy of the first, so that
+        // interpolation never has to wrap around
+        offsets[level + 1] = offsets[level] + (LEVEL_LENS[level] + 1) * FRAMES_PER_WT;
+        level += 1;
+    }
 
-    assert_eq!(spec.channels, 1, "only mono supported");
-    assert_eq!(
-        spec.sample_format,
-        SampleFormat::Float,
-        "Only FP samples supported"
-    );
+    offsets
+}
 
-    let mut samples = reader.into_samples::<f32>().map(Result::unwrap);
+/// Bandlimited wavetable data structure. Every level (bandlimited copy) of the
+/// wavetable, from the least to the most detailed, is stored in one flat slice, one
+/// frame after the other, so any sample of any level is one (gathered) index away.
+/// Levels with few harmonics are stored with shorter frames, see `LEVEL_LENS`.
+/// Tables are shared, read-only, by every oscillator playing the same wavetable.
+#[derive(Default)]
+pub(super) struct BandlimitedWaveTables {
+    data: Option<Arc<MipMaps>>,
+}
 
-    assert_eq!(
-        samples.len(),
-        WAVE_FRAME_LEN * FRAMES_PER_WT,
-        "invalid wavetable size, wavetable size must be {WAVE_FRAME_LEN} x {FRAMES_PER_WT} samples"
-    );
+/// Bandlimited copies of a wavetable, laid out as described in `BandlimitedWaveTables`
+type MipMaps = [f32];
 
-    for buffer in wt.iter_mut() {
-        let (wrap_around, window) = buffer.split_last_mut().unwrap();
+/// The tables of every wavetable in use in the process, by content hash. Tables
+/// are freed as soon as no oscillator uses them, their entries are removed later.
+/// On a hash collision, the tables already cached stay, the others aren't cached.
+static TABLE_CACHE: OnceLock<Mutex<HashMap<u64, Weak<MipMaps>>>> = OnceLock::new();
 
-        window
-            .iter_mut()
-            .zip(samples.by_ref())
-            .for_each(|(output, input)| *output = input);
+fn content_hash(wt: &WaveTable) -> u64 {
+    let mut hasher = DefaultHasher::new();
 
-        *wrap_around = window[0];
+    for frame in wt.iter() {
+        frame.iter().for_each(|sample| sample.to_bits().hash(&mut hasher));
     }
+
+    hasher.finish()
 }
 
-/// Bandlimited wavetable data structure
-#[derive(Default)]
-pub(super) struct BandlimitedWaveTables {
-    data: Option<Box<[WaveTable ; NUM_WAVETABLES]>>,
+/// Whether `tables` are those of `wt`, hashes alone can collide. The most
+/// detailed level is a copy of the wavetable, so it can be compared directly.
+fn tables_of(tables: &MipMaps, wt: &WaveTable) -> bool {
+    let full_level = &tables[LEVEL_OFFSETS[NUM_WAVETABLES - 1]..];
+
+    full_level
+        .chunks_exact(WAVE_FRAME_LEN + 1)
+        .zip(wt.iter())
+        .all(|(table, frame)| table == frame)
 }
 
 impl BandlimitedWaveTables {
+    /// Computes the tables of `wt`, unless they are in the cache already
     pub fn set_wavetable(&mut self, wt: &WaveTable) {
 
+        let hash = content_hash(wt);
+        let cache = TABLE_CACHE.get_or_init(Default::default);
+
+        {
+            let mut cache = cache.lock();
+            cache.retain(|_, tables| tables.strong_count() > 0);
+
+            let cached = cache.get(&hash).and_then(Weak::upgrade);
+            if let Some(tables) = cached.filter(|tables| tables_of(tables, wt)) {
+                self.data.replace(tables);
+                return;
+            }
+        }
+
+        // don't hold the lock for the (long) computation, in the rare case
+        // the same tables are computed concurrently, the first ones win
         let spectra = spectra_from_wavetable(wt);
+        let tables = Arc::<MipMaps>::from(bandlimited_wavetables(wt, spectra.as_ref()));
+
+        let mut cache = cache.lock();
+        let tables = match cache.get(&hash).and_then(Weak::upgrade) {
+            Some(cached) if tables_of(&cached, wt) => cached,
+            // another wavetable with the same hash, leave it there
+            Some(_) => tables,
+            None => {
+                cache.insert(hash, Arc::downgrade(&tables));
+                tables
+            }
+        };
+
+        self.data.replace(tables);
+    }
 
-        self.data.replace(bandlimited_wavetables(*wt, spectra.as_ref()));
+    /// Index of the most detailed level that doesn't alias when played with the given
+    /// phase increments, the level at index `n > 0` has `2^(n - 1)` harmonics
+    #[inline]
+    fn levels<const LANES: usize>(phase_delta: Simd<f32, LANES>) -> Simd<usize, LANES>
+    where
+        LaneCount<LANES>: SupportedLaneCount,
+    {
+        let norm_phase_delta = phase_delta * Simd::splat(1. / PHASE_RANGE);
+        let exponents = norm_phase_delta.to_bits() >> Simd::splat(23);
+
+        // (biased) exponent e plays up to 2^(126 - e) harmonics below nyquist
+        let levels = Simd::splat(126).saturating_sub(exponents).cast::<usize>();
+        let max = Simd::splat(NUM_WAVETABLES - 1);
+
+        levels.simd_gt(max).select(max, levels)
     }
 
     /// Resample the value at the given `frame` and `phase` `phase_delta` is
     /// the magnitude of the last phase increment of the oscillator and is used to determine
     /// which bandlimited copy of the wavetable to resample from, reducing aliasing.
+    /// Every lane is resampled independently.
     #[inline]
-    pub fn get_sample(&self, phase: f32x2, frame: usizex2, mut phase_delta: f32x2) -> f32x2 {
-
-        phase_delta *= f32x2::splat(1. / PHASE_RANGE);
-        let array = phase_delta.as_array();
-
-        let index = usizex2::splat(126).saturating_sub(
-                usizex2::from_array([array[0].to_bits() as usize, array[1].to_bits() as usize])
-        ) >> usizex2::splat(23);
-
-        unsafe {
-            // TODO: SIMD this later
-            // omit bounds checks
-            f32x2::from_array([
-                lerp_table(
-                    self.data.as_ref().unwrap_unchecked()
-                        .get_unchecked(index.as_array()[0].min(NUM_WAVETABLES - 1))
-                        .get_unchecked(frame.as_array()[0])
-                        .as_slice(),
-                    phase.as_array()[0],
-                ),
-                lerp_table(
-                    self.data.as_ref().unwrap_unchecked()
-                        .get_unchecked(index.as_array()[1].min(NUM_WAVETABLES - 1))
-                        .get_unchecked(frame.as_array()[1])
-                        .as_slice(),
-                    phase.as_array()[1],
-                )
-            ])
-        }
+    pub fn get_sample<const LANES: usize>(
+        &self,
+        phase: Simd<f32, LANES>,
+        frame: Simd<usize, LANES>,
+        phase_delta: Simd<f32, LANES>,
+    ) -> Simd<f32, LANES>
+    where
+        LaneCount<LANES>: SupportedLaneCount,
+    {
+        let Some(data) = self.data.as_ref().map(|tables| &tables[..]) else {
+            return Simd::splat(0.);
+        };
+
+        let (start, frac) = Self::sample_indices(phase, frame, Self::levels(phase_delta));
+
+        let enable = Mask::splat(true);
+        let zero = Simd::splat(0.);
+
+        // SAFETY: frames are less than FRAMES_PER_WT and phases
+        // are in [0 ; PHASE_RANGE), so `start + 1` is always in bounds
+        let (y0, y1) = unsafe {
+            (
+                Simd::gather_select_unchecked(data, enable, start, zero),
+                Simd::gather_select_unchecked(data, enable, start + Simd::splat(1), zero),
+            )
+        };
+
+        y0 + (y1 - y0) * frac
+    }
+
+    /// Index of the sample at, or right before, `phase`, in the tables of `frame` at
+    /// `levels`, and how far `phase` is from it, to the next (at the index after it)
+    #[inline]
+    fn sample_indices<const LANES: usize>(
+        phase: Simd<f32, LANES>,
+        frame: Simd<usize, LANES>,
+        levels: Simd<usize, LANES>,
+    ) -> (Simd<usize, LANES>, Simd<f32, LANES>)
+    where
+        LaneCount<LANES>: SupportedLaneCount,
+    {
+        let enable = Mask::splat(true);
+        let zero = Simd::splat(0);
+
+        // SAFETY: levels are clamped to the number of levels
+        let (offsets, lens) = unsafe {
+            (
+                Simd::gather_select_unchecked(&LEVEL_OFFSETS, enable, levels, zero),
+                Simd::gather_select_unchecked(&LEVEL_LENS, enable, levels, zero),
+            )
+        };
+
+        // phases, scaled to the length of the frames of their level
+        let phase = phase * lens.cast::<f32>() * Simd::splat(1. / PHASE_RANGE);
+        let index = phase.cast::<usize>();
+        let frac = phase - index.cast::<f32>();
+
+        (offsets + frame * (lens + Simd::splat(1)) + index, frac)
     }
 }
 
@@ -125,44 +250,157 @@ pub fn spectra_from_wavetable(wavetable: &WaveTable) -> Box<[Spectrum ; FRAMES_P
 /// Computes bandlimited copies of the wavetable with the given
 /// frequecncy spectra. The first will be DC. The second will have one
 /// harmonic, the third 2, the forth 4, the fifth 8, etc...
+/// They are laid out as described in `BandlimitedWaveTables`.
 pub fn bandlimited_wavetables(
-    wavetable: WaveTable,
+    wavetable: &WaveTable,
     spectra: &[Spectrum; FRAMES_PER_WT],
-) -> Box<[WaveTable ; NUM_WAVETABLES]> {
+) -> Box<[f32]> {
+
+    let mut output = vec![0.; LEVEL_OFFSETS[NUM_WAVETABLES]].into_boxed_slice();
 
-    let mut output = Vec::with_capacity(NUM_WAVETABLES);
-    output.push(empty_wavetable());
-    // SAFETY: len == capacity & elements will be initialized before this function returns
-    unsafe { output.set_len(NUM_WAVETABLES) };
+    let full_level = NUM_WAVETABLES - 1;
+    let full_wt = &mut output[LEVEL_OFFSETS[full_level]..LEVEL_OFFSETS[full_level + 1]];
 
-    let (full_wt, bandlimited_versions) = output.split_last_mut().unwrap();
-    *full_wt = wavetable;
+    for (table, frame) in full_wt.chunks_exact_mut(WAVE_FRAME_LEN + 1).zip(wavetable.iter()) {
+        table.copy_from_slice(frame);
+    }
 
     let mut c2r = realfft::RealFftPlanner::<f32>::new();
-    let fft = c2r.plan_fft_inverse(WAVE_FRAME_LEN);
-    let mut scratch = [Complex32::new(0., 0.); SPECTRUM_SIZE];
-    let mut input = scratch;
+    let mut spectrum_buf = [Complex32::new(0., 0.); SPECTRUM_SIZE];
 
-    let mut partials = 1;
+    // DC is removed, the first level stays silent
+    for level in 1..full_level {
+        let len = LEVEL_LENS[level];
+        let terrain = &mut output[LEVEL_OFFSETS[level]..LEVEL_OFFSETS[level + 1]];
 
-    for terrain in bandlimited_versions[1..].iter_mut() {
-        let bins = partials + 1;
+        let fft = c2r.plan_fft_inverse(len);
+        let mut scratch = fft.make_scratch_vec();
+        let input = &mut spectrum_buf[..len / 2 + 1];
 
-        for (spectrum, table) in spectra.iter().zip(terrain.iter_mut()) {
+        let bins = (1 << (level - 1)) + 1;
+
+        for (spectrum, table) in spectra.iter().zip(terrain.chunks_exact_mut(len + 1)) {
             let (pass_band, stop_band) = input.split_at_mut(bins);
             pass_band.copy_from_slice(&spectrum[..bins]);
             stop_band.fill(Complex32::new(0., 0.));
 
             let (wrap_around, window) = table.split_last_mut().unwrap();
 
-            fft.process_with_scratch(&mut input, window, &mut scratch)
+            fft.process_with_scratch(input, window, &mut scratch)
                 .unwrap();
 
-            let normalize = (window.len() * 2) as f32;
+            // the spectra are those of full length frames, going back and forth scales them by it
+            let normalize = WAVE_FRAME_LEN as f32;
             window.iter_mut().for_each(|sample| *sample /= normalize);
             *wrap_around = window[0];
         }
-        partials *= 2;
     }
-    output.try_into().unwrap()
+    output
+}
+
+#[cfg(test)]
+mod bench;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    /// Harmonics of the test wavetable, and their amplitudes, the
+    /// highest is only played by the (full length) last level
+    const HARMONICS: [(usize, f32); 5] = [(1, 1.), (3, 0.5), (8, 0.25), (100, 0.2), (1000, 0.1)];
+
+    /// Gain of `frame` in the test wavetable, to tell frames apart
+    fn frame_gain(frame: usize) -> f32 {
+        (frame + 1) as f32 / FRAMES_PER_WT as f32
+    }
+
+    /// Value of `frame` of the test wavetable at `phase` (from 0 to 1),
+    /// with only the harmonics up to `max_harmonic`
+    fn bandlimited(frame: usize, phase: f32, max_harmonic: usize) -> f32 {
+        let sum = HARMONICS
+            .iter()
+            .filter(|&&(harmonic, _)| harmonic <= max_harmonic)
+            .map(|&(harmonic, amplitude)| amplitude * (TAU * harmonic as f32 * phase).sin())
+            .sum::<f32>();
+
+        frame_gain(frame) * sum
+    }
+
+    fn test_tables() -> BandlimitedWaveTables {
+        let mut wavetable = vec![[0.; WAVE_FRAME_LEN + 1]; FRAMES_PER_WT];
+
+        for (n, frame) in wavetable.iter_mut().enumerate() {
+            for (i, sample) in frame.iter_mut().enumerate() {
+                *sample = bandlimited(n, i as f32 / WAVE_FRAME_LEN as f32, usize::MAX);
+            }
+        }
+
+        let mut tables = BandlimitedWaveTables::default();
+        tables.set_wavetable(wavetable.as_slice().try_into().unwrap());
+        tables
+    }
+
+    /// Phase increment for which `BandlimitedWaveTables::levels` picks `level`
+    fn phase_delta(level: usize) -> f32 {
+        PHASE_RANGE * 0.5f32.powi(level as i32 + 1)
+    }
+
+    #[test]
+    fn picks_levels() {
+        for level in 0..NUM_WAVETABLES {
+            let levels = BandlimitedWaveTables::levels(Simd::<f32, 1>::splat(phase_delta(level)));
+            assert_eq!(levels[0], level);
+        }
+
+        // very low notes play the most detailed level
+        let levels = BandlimitedWaveTables::levels(Simd::<f32, 1>::splat(1e-6));
+        assert_eq!(levels[0], NUM_WAVETABLES - 1);
+    }
+
+    #[test]
+    fn stays_in_bounds() {
+        let last_phase = f32::from_bits(PHASE_RANGE.to_bits() - 1);
+
+        for level in 0..NUM_WAVETABLES {
+            let (start, frac) = BandlimitedWaveTables::sample_indices(
+                Simd::<f32, 1>::splat(last_phase),
+                Simd::splat(FRAMES_PER_WT - 1),
+                Simd::splat(level),
+            );
+
+            // the last sample read is the wrap-around one of the level's last frame
+            assert_eq!(start[0] + 1, LEVEL_OFFSETS[level + 1] - 1, "level {level}");
+            assert!(start[0] + 1 < LEVEL_OFFSETS[NUM_WAVETABLES]);
+            assert!((0. ..1.).contains(&frac[0]));
+        }
+    }
+
+    #[test]
+    fn renders_every_level() {
+        let tables = test_tables();
+
+        for level in 0..NUM_WAVETABLES {
+            let len = LEVEL_LENS[level];
+            // the first level is silent, the others have twice the harmonics of the previous
+            let max_harmonic = if level == 0 { 0 } else { 1 << (level - 1) };
+
+            for frame in [0, 1, 128, FRAMES_PER_WT - 1] {
+                // phases right on samples of the level, nothing gets interpolated
+                for i in [0, 1, len / 3, len / 2, len - 1] {
+                    let sample = tables.get_sample(
+                        Simd::<f32, 1>::splat(i as f32 * PHASE_RANGE / len as f32),
+                        Simd::splat(frame),
+                        Simd::splat(phase_delta(level)),
+                    )[0];
+
+                    let expected = bandlimited(frame, i as f32 / len as f32, max_harmonic);
+                    assert!(
+                        (sample - expected).abs() < 1e-3,
+                        "level {level}, frame {frame}, sample {i}: {sample}, expected {expected}"
+                    );
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,103 @@
+//! Per-oscillator cost of `BandlimitedWaveTables::get_sample`, run with `cargo bench`.
+//! Every iteration reads one sample from each of `NUM_OSCILLATORS` oscillators,
+//! in vectors of `LANES` lanes, divide the reported time by `NUM_OSCILLATORS`.
+
+extern crate test;
+
+use super::*;
+use plugin_util::dsp::lerp_table;
+use std::array;
+use test::{black_box, Bencher};
+
+const NUM_OSCILLATORS: usize = MAX_POLYPHONY * 16;
+
+fn saw_wavetable() -> Vec<WaveFrame> {
+    let mut wavetable = vec![[0.; WAVE_FRAME_LEN + 1]; FRAMES_PER_WT];
+
+    for frame in wavetable.iter_mut() {
+        for (i, sample) in frame.iter_mut().enumerate() {
+            *sample = (i % WAVE_FRAME_LEN) as f32 / WAVE_FRAME_LEN as f32 * 2. - 1.;
+        }
+    }
+
+    wavetable
+}
+
+fn saw_tables() -> BandlimitedWaveTables {
+    let mut tables = BandlimitedWaveTables::default();
+    tables.set_wavetable(saw_wavetable().as_slice().try_into().unwrap());
+    tables
+}
+
+/// The layout before the flat one, every level a full length wavetable of its own,
+/// only the lookup is benchmarked, so every level holds the same (full) wavetable
+struct NestedWaveTables(Vec<Vec<WaveFrame>>);
+
+impl NestedWaveTables {
+    fn saw() -> Self {
+        Self(vec![saw_wavetable(); NUM_WAVETABLES])
+    }
+
+    /// The lookup as it was then, one scalar interpolation per lane, with
+    /// today's level selection, the one it had then always picked the silent level
+    fn get_sample(
+        &self,
+        phase: VoiceVector,
+        frame: Simd<usize, LANES>,
+        phase_delta: VoiceVector,
+    ) -> VoiceVector {
+        let levels = BandlimitedWaveTables::levels(phase_delta);
+
+        // SAFETY: levels are clamped to the number of levels, and frames are less than FRAMES_PER_WT
+        VoiceVector::from_array(array::from_fn(|lane| unsafe {
+            lerp_table(
+                self.0
+                    .get_unchecked(levels[lane])
+                    .get_unchecked(frame[lane])
+                    .as_slice(),
+                phase[lane],
+            )
+        }))
+    }
+}
+
+/// Phases, frames and phase increments of `NUM_OSCILLATORS` detuned oscillators
+fn oscillators() -> Vec<(VoiceVector, Simd<usize, LANES>, VoiceVector)> {
+    (0..NUM_OSCILLATORS / LANES)
+        .map(|i| {
+            let lanes = |f: fn(usize) -> f32| {
+                VoiceVector::from_array(array::from_fn(|j| f(i * LANES + j)))
+            };
+
+            (
+                lanes(|n| (n * 997 % WAVE_FRAME_LEN) as f32 + 0.5),
+                Simd::from_array(array::from_fn(|j| (i * LANES + j) % FRAMES_PER_WT)),
+                lanes(|n| 5. + n as f32 * 0.01),
+            )
+        })
+        .collect()
+}
+
+#[bench]
+fn nested(b: &mut Bencher) {
+    let tables = NestedWaveTables::saw();
+    let oscillators = oscillators();
+
+    b.iter(|| {
+        for &(phase, frame, phase_delta) in oscillators.iter() {
+            black_box(tables.get_sample(phase, frame, phase_delta));
+        }
+    });
+}
+
+#[bench]
+fn gather(b: &mut Bencher) {
+    let tables = saw_tables();
+    let oscillators = oscillators();
+
+    b.iter(|| {
+        for &(phase, frame, phase_delta) in oscillators.iter() {
+            black_box(tables.get_sample(phase, frame, phase_delta));
+        }
+    });
+}
@@ -0,0 +1,207 @@
+use super::*;
+use atomic_float::AtomicF32;
+use atomic_refcell::AtomicRefCell;
+use modulation::{routes_ui, ModRoute};
+use nodes::midi_learn::MidiLearn;
+use plugin_util::{gui::widgets::*, parameter::ParamHandle};
+use std::sync::atomic::{AtomicU32, Ordering};
+use tuning::{tuning_ui, Tuning, TuningEditor};
+
+/// Parameters of the whole plugin, wrapping those of its top-level node
+#[derive(Params)]
+pub struct SeenthPluginParams<T: SeenthStandAlonePlugin> {
+    #[id = "play_mode"]
+    pub play_mode: EnumParam<PlayMode>,
+    #[id = "glide"]
+    pub glide: FloatParam,
+    #[id = "steal"]
+    pub steal_mode: EnumParam<VoiceStealMode>,
+    #[id = "bend_up"]
+    pub bend_up: IntParam,
+    #[id = "bend_down"]
+    pub bend_down: IntParam,
+    #[id = "mpe"]
+    pub mpe: BoolParam,
+    #[id = "mpe_bend"]
+    pub mpe_bend_range: IntParam,
+    #[persist = "mod_routes"]
+    pub mod_routes: AtomicRefCell<Vec<ModRoute>>,
+    #[persist = "tuning"]
+    pub tuning: AtomicRefCell<Tuning>,
+    /// frequency of every note in `tuning`, `NAN` if it isn't mapped to anything,
+    /// for the audio thread to read without waiting for the editor, see `tuning_changed`
+    note_freqs: [AtomicF32; 128],
+    /// incremented every time `note_freqs` are recomputed
+    tuning_version: AtomicU32,
+    /// notes dropped by the audio thread, as every voice was taken, shown in the editor
+    dropped_notes: AtomicU32,
+    tuning_editor: AtomicRefCell<TuningEditor>,
+    #[nested(group = "MIDI Learn")]
+    pub midi_learn: MidiLearn,
+    #[nested(group = "Synth")]
+    pub node: Arc<T>,
+}
+
+impl<T: SeenthStandAlonePlugin> Default for SeenthPluginParams<T> {
+    fn default() -> Self {
+        Self {
+            play_mode: EnumParam::new("Play Mode", PlayMode::Poly),
+            glide: FloatParam::new(
+                "Glide",
+                0.,
+                FloatRange::Skewed {
+                    min: 0.,
+                    max: 5000.,
+                    factor: FloatRange::skew_factor(-2.),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(v2s_f32_rounded(1)),
+            steal_mode: EnumParam::new("Voice Stealing", VoiceStealMode::Oldest),
+            bend_up: bend_range("Bend Up"),
+            bend_down: bend_range("Bend Down"),
+            mpe: BoolParam::new("MPE", false),
+            mpe_bend_range: IntParam::new("MPE Bend", 48, IntRange::Linear { min: 0, max: 96 })
+                .with_unit(" st"),
+            mod_routes: Default::default(),
+            tuning: Default::default(),
+            note_freqs: array::from_fn(|note| {
+                AtomicF32::new(Tuning::default().freq(note as u8).unwrap_or(f32::NAN))
+            }),
+            tuning_version: AtomicU32::new(0),
+            dropped_notes: AtomicU32::new(0),
+            tuning_editor: Default::default(),
+            midi_learn: Default::default(),
+            node: Default::default(),
+        }
+    }
+}
+
+fn bend_range(name: &str) -> IntParam {
+    IntParam::new(name, 2, IntRange::Linear { min: 0, max: 48 }).with_unit(" st")
+}
+
+impl<T: SeenthStandAlonePlugin> SeenthPluginParams<T> {
+    /// Pitch offset, in semitones, of a (normalized, from -1 to 1) pitch wheel position
+    pub fn bend_semitones(&self, bend: f32) -> f32 {
+        let range = if bend > 0. { &self.bend_up } else { &self.bend_down };
+        bend * range.value() as f32
+    }
+
+    /// Frequency of `note` in the loaded tuning, in Hz, `None` if it isn't mapped to anything
+    pub fn note_freq(&self, note: u8) -> Option<f32> {
+        let freq = self.note_freqs.get(note as usize)?.load(Ordering::Relaxed);
+        (!freq.is_nan()).then_some(freq)
+    }
+
+    /// Recomputes the frequencies returned by `note_freq`, to be called
+    /// whenever `tuning` changes, including when it is restored
+    pub fn tuning_changed(&self) {
+        // the state is being restored, `initialize` calls this again afterwards
+        let Ok(tuning) = self.tuning.try_borrow() else {
+            return;
+        };
+
+        for (note, freq) in self.note_freqs.iter().enumerate() {
+            freq.store(tuning.freq(note as u8).unwrap_or(f32::NAN), Ordering::Relaxed);
+        }
+
+        self.tuning_version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Changes every time the tuning does, see `tuning_changed`
+    pub fn tuning_version(&self) -> u32 {
+        self.tuning_version.load(Ordering::Acquire)
+    }
+
+    /// Counts a note the audio thread couldn't find a voice for
+    pub fn note_dropped(&self) {
+        self.dropped_notes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ui(&self, ui: &mut Ui, setter: &ParamSetter) -> Response {
+        ui.horizontal(|ui| {
+            enum_combo_box(ui, &self.play_mode, setter);
+
+            ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
+                (&self.glide, setter).into(),
+            ));
+
+            enum_combo_box(ui, &self.steal_mode, setter);
+
+            ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
+                (&self.bend_down, setter).into(),
+            ));
+
+            ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
+                (&self.bend_up, setter).into(),
+            ));
+
+            let mut mpe = self.mpe.value();
+            if ui.checkbox(&mut mpe, self.mpe.name()).changed() {
+                setter.begin_set_parameter(&self.mpe);
+                setter.set_parameter(&self.mpe, mpe);
+                setter.end_set_parameter(&self.mpe);
+            }
+
+            ui.add(ParamWidget::<Knob, ParamHandle<_>>::default(
+                (&self.mpe_bend_range, setter).into(),
+            ));
+
+            ui.menu_button("Modulation", |ui| {
+                // the audio thread might be reading the routes, skip this frame if so
+                if let Ok(mut routes) = self.mod_routes.try_borrow_mut() {
+                    routes_ui(ui, &mut routes, self.node.modulation_targets());
+                }
+            });
+
+            ui.menu_button("Tuning", |ui| {
+                // the state might be being saved, skip this frame if so, the
+                // audio thread only ever reads `note_freqs`, it never waits for us
+                let changed = match self.tuning.try_borrow_mut() {
+                    Ok(mut tuning) => {
+                        tuning_ui(ui, &mut tuning, &mut self.tuning_editor.borrow_mut())
+                    }
+                    Err(_) => false,
+                };
+
+                if changed {
+                    self.tuning_changed();
+                }
+            });
+
+            let dropped_notes = self.dropped_notes.load(Ordering::Relaxed);
+            if dropped_notes > 0 {
+                let clear = ui
+                    .button(format!("{dropped_notes} notes dropped"))
+                    .on_hover_text("every voice was taken, click to clear");
+
+                if clear.clicked() {
+                    self.dropped_notes.store(0, Ordering::Relaxed);
+                }
+            }
+        })
+        .response
+    }
+}
+
+fn enum_combo_box<E: Enum + PartialEq + 'static>(
+    ui: &mut Ui,
+    param: &EnumParam<E>,
+    setter: &ParamSetter,
+) -> Response {
+    let current = param.value().to_index();
+
+    ComboBox::from_label(param.name())
+        .selected_text(E::variants()[current])
+        .show_ui(ui, |ui| {
+            for (i, &name) in E::variants().iter().enumerate() {
+                if ui.selectable_label(i == current, name).clicked() {
+                    setter.begin_set_parameter(param);
+                    setter.set_parameter(param, E::from_index(i));
+                    setter.end_set_parameter(param);
+                }
+            }
+        })
+        .response
+}
@@ -0,0 +1,465 @@
+pub mod mts;
+
+use super::*;
+use serde::{Deserialize, Serialize};
+use std::fs::read_to_string;
+
+/// MIDI note the 12-TET frequencies are given relative to
+const A4: u8 = 69;
+const A4_FREQ: f32 = 440.;
+const MIDDLE_C: u8 = 60;
+
+/// A scale, as described in a Scala (.scl) file
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Scale {
+    pub description: String,
+    /// pitch of every degree of the scale, in cents above the first (implicit, 1/1) one,
+    /// the last degree is the period of the scale, usually an octave (1200 cents)
+    pub cents: Vec<f32>,
+}
+
+impl Scale {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut lines = non_comment_lines(source);
+
+        let description = lines
+            .next()
+            .ok_or("missing description line")?
+            .trim()
+            .to_owned();
+
+        let len_line = lines.next().ok_or("missing number of notes")?;
+        let len = first_word(len_line)
+            .parse::<usize>()
+            .map_err(|_| format!("invalid number of notes: {len_line}"))?;
+
+        let cents = lines
+            .take(len)
+            .map(|line| parse_pitch(first_word(line)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if cents.len() != len {
+            return Err(format!("expected {len} notes, found {}", cents.len()));
+        }
+
+        if cents.is_empty() {
+            return Err("the scale has no notes".into());
+        }
+
+        Ok(Self { description, cents })
+    }
+
+    /// Pitch, in cents, of `degree`, which may lie outside of the
+    /// first period of the scale, in either direction
+    fn degree_cents(&self, degree: i32) -> f32 {
+        let len = self.cents.len() as i32;
+        let period = self.cents[self.cents.len() - 1];
+
+        let (periods, degree) = (degree.div_euclid(len), degree.rem_euclid(len));
+
+        let cents = match degree {
+            0 => 0.,
+            degree => self.cents[degree as usize - 1],
+        };
+
+        periods as f32 * period + cents
+    }
+}
+
+/// How the degrees of a scale are laid out on the keyboard, as described
+/// in a Scala keyboard mapping (.kbm) file
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeyboardMapping {
+    pub first_note: u8,
+    pub last_note: u8,
+    /// note playing the first entry of `mapping`
+    pub middle_note: u8,
+    /// note whose frequency is `reference_freq`
+    pub reference_note: u8,
+    pub reference_freq: f32,
+    /// degree of the scale that `mapping` repeats at
+    pub period_degree: i32,
+    /// scale degree played by every key of the pattern, `None` for keys that don't play
+    /// anything. If empty, consecutive keys play consecutive degrees.
+    pub mapping: Vec<Option<i32>>,
+}
+
+impl KeyboardMapping {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut fields = non_comment_lines(source).map(first_word);
+
+        let mut next = |name: &str| fields.next().ok_or_else(|| format!("missing {name}"));
+
+        let size = parse_field::<usize>(next("map size")?, "map size")?;
+        let first_note = parse_field(next("first note")?, "first note")?;
+        let last_note = parse_field(next("last note")?, "last note")?;
+        let middle_note = parse_field(next("middle note")?, "middle note")?;
+        let reference_note = parse_field(next("reference note")?, "reference note")?;
+        let reference_freq = parse_field(next("reference frequency")?, "reference frequency")?;
+        let period_degree = parse_field(next("period degree")?, "period degree")?;
+
+        let mapping = (0..size)
+            .map(|_| match next("mapping entry")? {
+                "x" | "X" => Ok(None),
+                entry => parse_field(entry, "mapping entry").map(Some),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let notes: [u8; 4] = [first_note, last_note, middle_note, reference_note];
+        if first_note > last_note || notes.iter().any(|&note| note > 127) {
+            return Err("invalid note range".into());
+        }
+
+        if !(reference_freq > 0.) {
+            return Err("the reference frequency must be positive".into());
+        }
+
+        let keyboard_mapping = Self {
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_freq,
+            period_degree,
+            mapping,
+        };
+
+        if keyboard_mapping.degree(reference_note).is_none() {
+            return Err("the reference note isn't mapped to any degree".into());
+        }
+
+        Ok(keyboard_mapping)
+    }
+
+    /// Maps the middle note to the first degree of `scale`, and the reference
+    /// note to the frequency it has in 12-TET, like Scala does without a .kbm file
+    fn linear(scale: &Scale) -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: MIDDLE_C,
+            reference_note: MIDDLE_C,
+            reference_freq: equal_temperament(MIDDLE_C),
+            period_degree: scale.cents.len() as i32,
+            mapping: Vec::new(),
+        }
+    }
+
+    /// Degree of the scale played by `note`, if any
+    fn degree(&self, note: u8) -> Option<i32> {
+        if !(self.first_note..=self.last_note).contains(&note) {
+            return None;
+        }
+
+        let offset = note as i32 - self.middle_note as i32;
+
+        if self.mapping.is_empty() {
+            return Some(offset);
+        }
+
+        let len = self.mapping.len() as i32;
+        let (periods, key) = (offset.div_euclid(len), offset.rem_euclid(len));
+
+        self.mapping[key as usize].map(|degree| periods * self.period_degree + degree)
+    }
+}
+
+/// The tuning of the whole keyboard, 12-TET unless a scale is loaded
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Tuning {
+    pub scale: Option<Scale>,
+    /// if `None`, the default, linear, mapping is used
+    pub mapping: Option<KeyboardMapping>,
+}
+
+impl Tuning {
+    /// Frequency of `note`, in Hz, `None` if it isn't mapped to anything
+    pub fn freq(&self, note: u8) -> Option<f32> {
+        let Some(scale) = &self.scale else {
+            return Some(equal_temperament(note));
+        };
+
+        let linear;
+        let mapping = match &self.mapping {
+            Some(mapping) => mapping,
+            None => {
+                linear = KeyboardMapping::linear(scale);
+                &linear
+            }
+        };
+
+        let degree = mapping.degree(note)?;
+        let reference_degree = mapping.degree(mapping.reference_note)?;
+
+        let cents = scale.degree_cents(degree) - scale.degree_cents(reference_degree);
+
+        Some(mapping.reference_freq * (cents / 1200.).exp2())
+    }
+}
+
+fn equal_temperament(note: u8) -> f32 {
+    A4_FREQ * ((note as f32 - A4 as f32) / 12.).exp2()
+}
+
+/// Lines of a Scala file, without the comments
+fn non_comment_lines(source: &str) -> impl Iterator<Item = &str> {
+    source.lines().filter(|line| !line.starts_with('!'))
+}
+
+/// Anything after the first whitespace is a comment
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+fn parse_field<F: std::str::FromStr>(field: &str, name: &str) -> Result<F, String> {
+    field.parse().map_err(|_| format!("invalid {name}: {field}"))
+}
+
+/// Pitches with a period are in cents, the others are ratios, or integers
+fn parse_pitch(pitch: &str) -> Result<f32, String> {
+    let invalid = || format!("invalid pitch: {pitch}");
+
+    if pitch.contains('.') {
+        return pitch.parse().map_err(|_| invalid());
+    }
+
+    let (num, den) = pitch.split_once('/').unwrap_or((pitch, "1"));
+    let num = num.parse::<f64>().map_err(|_| invalid())?;
+    let den = den.parse::<f64>().map_err(|_| invalid())?;
+
+    if !(num > 0. && den > 0.) {
+        return Err(invalid());
+    }
+
+    Ok((1200. * (num / den).log2()) as f32)
+}
+
+/// Editor-only state of the tuning menu
+#[derive(Default)]
+pub struct TuningEditor {
+    scale_path: String,
+    mapping_path: String,
+    error: Option<String>,
+}
+
+/// Editor for the loaded tuning, returns whether it has changed
+pub fn tuning_ui(ui: &mut Ui, tuning: &mut Tuning, editor: &mut TuningEditor) -> bool {
+    let mut changed = false;
+
+    let scale_name = tuning
+        .scale
+        .as_ref()
+        .map_or("12-TET", |scale| scale.description.as_str());
+
+    ui.label(format!("Scale: {scale_name}"));
+
+    ui.horizontal(|ui| {
+        ui.text_edit_singleline(&mut editor.scale_path);
+        if ui.button("Load .scl").clicked() {
+            editor.error = read_to_string(&editor.scale_path)
+                .map_err(|err| err.to_string())
+                .and_then(|source| Scale::parse(&source))
+                .map(|scale| {
+                    tuning.scale = Some(scale);
+                    changed = true;
+                })
+                .err();
+        }
+    });
+
+    ui.horizontal(|ui| {
+        ui.text_edit_singleline(&mut editor.mapping_path);
+        if ui.button("Load .kbm").clicked() {
+            editor.error = read_to_string(&editor.mapping_path)
+                .map_err(|err| err.to_string())
+                .and_then(|source| KeyboardMapping::parse(&source))
+                .map(|mapping| {
+                    tuning.mapping = Some(mapping);
+                    changed = true;
+                })
+                .err();
+        }
+    });
+
+    if ui.button("Reset to 12-TET").clicked() {
+        *tuning = Tuning::default();
+        editor.error = None;
+        changed = true;
+    }
+
+    if let Some(error) = &editor.error {
+        ui.colored_label(Color32::RED, error.as_str());
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cents of a just fifth
+    const FIFTH: f32 = 701.955;
+
+    const SCALE: &str = "! test.scl
+!
+ fifths and semitones
+ 3
+!
+ 100.0 a semitone
+ 3/2
+ 2
+";
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!(
+            (value - expected).abs() <= expected.abs() * 1e-5 + 1e-3,
+            "{value}, expected {expected}"
+        );
+    }
+
+    fn scale() -> Scale {
+        Scale::parse(SCALE).unwrap()
+    }
+
+    #[test]
+    fn parses_pitches() {
+        assert_close(parse_pitch("100.0").unwrap(), 100.);
+        assert_close(parse_pitch("-5.5").unwrap(), -5.5);
+        assert_close(parse_pitch("3/2").unwrap(), FIFTH);
+        assert_close(parse_pitch("2").unwrap(), 1200.);
+
+        for invalid in ["0/1", "5/0", "-3/2", "abc", "1/2/3", ""] {
+            assert!(parse_pitch(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn parses_scales() {
+        let scale = scale();
+
+        assert_eq!(scale.description, "fifths and semitones");
+        assert_eq!(scale.cents.len(), 3);
+        for (cents, expected) in scale.cents.into_iter().zip([100., FIFTH, 1200.]) {
+            assert_close(cents, expected);
+        }
+
+        assert!(Scale::parse("too few notes\n 3\n 100.0\n 2/1\n").is_err());
+        assert!(Scale::parse("no notes\n 0\n").is_err());
+        assert!(Scale::parse("invalid note\n 1\n 2/x\n").is_err());
+        assert!(Scale::parse("! only comments\n").is_err());
+    }
+
+    #[test]
+    fn repeats_scales_every_period() {
+        let scale = scale();
+
+        for (degree, expected) in [
+            (0, 0.),
+            (2, FIFTH),
+            (3, 1200.),
+            (4, 1300.),
+            (-1, FIFTH - 1200.),
+            (-3, -1200.),
+            (-4, -2400. + FIFTH),
+        ] {
+            assert_close(scale.degree_cents(degree), expected);
+        }
+    }
+
+    #[test]
+    fn defaults_to_12_tet() {
+        let tuning = Tuning::default();
+
+        assert_close(tuning.freq(A4).unwrap(), A4_FREQ);
+        assert_close(tuning.freq(A4 - 12).unwrap(), A4_FREQ / 2.);
+        assert_close(tuning.freq(A4 + 3).unwrap(), A4_FREQ * 2f32.powf(3. / 12.));
+    }
+
+    #[test]
+    fn maps_scales_linearly_from_middle_c() {
+        let tuning = Tuning {
+            scale: Some(scale()),
+            mapping: None,
+        };
+
+        let middle_c = equal_temperament(MIDDLE_C);
+
+        assert_close(tuning.freq(MIDDLE_C).unwrap(), middle_c);
+        assert_close(tuning.freq(MIDDLE_C + 2).unwrap(), middle_c * 1.5);
+        assert_close(tuning.freq(MIDDLE_C + 4).unwrap(), middle_c * 2f32.powf(1300. / 1200.));
+        assert_close(tuning.freq(MIDDLE_C - 1).unwrap(), middle_c * 0.75);
+    }
+
+    /// Keyboard mapping with 4 keys, the second unmapped, repeating every 3 degrees
+    fn mapping(reference_note: u8) -> String {
+        format!(
+            "! test.kbm
+ 4 ! size
+ 0
+ 127
+ 60 ! middle note
+ {reference_note}
+ 440.0
+ 3 ! period degree
+ 0
+ x
+ 1
+ 2
+"
+        )
+    }
+
+    #[test]
+    fn parses_keyboard_mappings() {
+        let mapping = KeyboardMapping::parse(&mapping(68)).unwrap();
+
+        assert_eq!(mapping.mapping, [Some(0), None, Some(1), Some(2)]);
+
+        for (note, degree) in [
+            (60, Some(0)),
+            (61, None),
+            (62, Some(1)),
+            (64, Some(3)),
+            (65, None),
+            (59, Some(-1)),
+            (56, Some(-3)),
+            (68, Some(6)),
+        ] {
+            assert_eq!(mapping.degree(note), degree, "note {note}");
+        }
+    }
+
+    #[test]
+    fn tunes_mapped_keyboards() {
+        let tuning = Tuning {
+            scale: Some(scale()),
+            mapping: Some(KeyboardMapping::parse(&mapping(68)).unwrap()),
+        };
+
+        // the reference note, two periods above the middle note
+        assert_close(tuning.freq(68).unwrap(), 440.);
+        assert_close(tuning.freq(64).unwrap(), 220.);
+        assert_close(tuning.freq(60).unwrap(), 110.);
+        assert_close(tuning.freq(62).unwrap(), 110. * 2f32.powf(100. / 1200.));
+        assert_close(tuning.freq(59).unwrap(), 110. * 2f32.powf((FIFTH - 1200.) / 1200.));
+        assert_eq!(tuning.freq(61), None);
+    }
+
+    #[test]
+    fn rejects_invalid_keyboard_mappings() {
+        // the reference note falls on the unmapped key
+        assert!(KeyboardMapping::parse(&mapping(69)).is_err());
+        // missing mapping entries
+        assert!(KeyboardMapping::parse("4\n0\n127\n60\n60\n440.0\n3\n0\nx\n1\n").is_err());
+        // first note after last
+        assert!(KeyboardMapping::parse("0\n100\n50\n60\n60\n440.0\n12\n").is_err());
+        // notes out of range
+        assert!(KeyboardMapping::parse("0\n0\n128\n60\n60\n440.0\n12\n").is_err());
+        // reference frequency
+        assert!(KeyboardMapping::parse("0\n0\n127\n60\n60\n0\n12\n").is_err());
+        assert!(KeyboardMapping::parse("0\n0\n127\n60\n60\nx\n12\n").is_err());
+
+        assert!(KeyboardMapping::parse("0\n0\n127\n60\n60\n440.0\n12\n").is_ok());
+    }
+}
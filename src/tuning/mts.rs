@@ -0,0 +1,237 @@
+use super::*;
+use arrayvec::ArrayVec;
+
+const NON_REAL_TIME: u8 = 0x7E;
+const REAL_TIME: u8 = 0x7F;
+const MIDI_TUNING: u8 = 0x08;
+
+const BULK_DUMP: u8 = 0x01;
+const SINGLE_NOTE: u8 = 0x02;
+const BANK_BULK_DUMP: u8 = 0x04;
+const BANK_SINGLE_NOTE: u8 = 0x07;
+
+const NAME_LEN: usize = 16;
+
+/// Pitch of a note, in MTS format: a MIDI note, and a 14-bit fraction of a semitone above it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtsPitch([u8; 3]);
+
+impl MtsPitch {
+    /// Pitch, in (fractional) MIDI notes, `None` if the pitch is left unchanged
+    pub fn note(self) -> Option<f32> {
+        let [semitone, msb, lsb] = self.0;
+
+        if self.0 == [0x7F; 3] {
+            return None;
+        }
+
+        let fraction = (msb as u16) << 7 | lsb as u16;
+        Some(semitone as f32 + fraction as f32 / (1 << 14) as f32)
+    }
+}
+
+/// Retuning carried by a MIDI Tuning Standard message, any other SysEx message is ignored.
+/// Every kind of message is applied into the same delta, the new pitch of every note,
+/// `7F 7F 7F` for notes left unchanged, which keeps it `Copy`, and as small as it gets
+/// (3 bytes per note), as every `NoteEvent` is as large as its largest message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtsMessage([MtsPitch; 128]);
+
+impl MtsMessage {
+    const UNCHANGED: Self = Self([MtsPitch([0x7F; 3]); 128]);
+
+    /// Every note retuned by this message, with its new pitch, in (fractional) MIDI notes
+    pub fn changes(&self) -> ArrayVec<(u8, f32), 128> {
+        (0..128)
+            .zip(self.0.iter().copied())
+            .filter_map(|(note, pitch)| pitch.note().map(|pitch| (note, pitch)))
+            .collect()
+    }
+
+    /// Retunes the whole keyboard
+    fn parse_pitches(data: &[u8]) -> Option<Self> {
+        let mut message = Self::UNCHANGED;
+
+        for (pitch, bytes) in message.0.iter_mut().zip(data.array_chunks::<3>()) {
+            *pitch = MtsPitch(*bytes);
+        }
+
+        (data.len() >= 128 * 3).then_some(message)
+    }
+
+    /// Retunes some notes, if a note is retuned more than once, the last pitch wins
+    fn parse_notes(data: &[u8]) -> Option<Self> {
+        let (&count, data) = data.split_first()?;
+
+        if count > 127 || data.len() < count as usize * 4 {
+            return None;
+        }
+
+        let mut message = Self::UNCHANGED;
+
+        for &[key, semitone, msb, lsb] in data.array_chunks::<4>().take(count as usize) {
+            *message.0.get_mut(key as usize)? = MtsPitch([semitone, msb, lsb]);
+        }
+
+        Some(message)
+    }
+}
+
+impl SysExMessage for MtsMessage {
+    // these messages are only received
+    type Buffer = [u8; 0];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        let buffer = buffer.strip_prefix(&[0xF0]).unwrap_or(buffer);
+        let buffer = buffer.strip_suffix(&[0xF7]).unwrap_or(buffer);
+
+        // the device ID is ignored, we respond to all of them
+        let &[universal, _device_id, MIDI_TUNING, sub_id, ref data @ ..] = buffer else {
+            return None;
+        };
+
+        // the checksums of the bulk dumps are ignored as well, as many devices get them wrong
+        match (universal, sub_id) {
+            (NON_REAL_TIME, BULK_DUMP) => {
+                // program number, then name
+                Self::parse_pitches(data.get(1 + NAME_LEN..)?)
+            }
+            (NON_REAL_TIME, BANK_BULK_DUMP) => {
+                // bank and program numbers, then name
+                Self::parse_pitches(data.get(2 + NAME_LEN..)?)
+            }
+            (REAL_TIME, SINGLE_NOTE) => {
+                // program number
+                Self::parse_notes(data.get(1..)?)
+            }
+            (NON_REAL_TIME | REAL_TIME, BANK_SINGLE_NOTE) => {
+                // bank and program numbers
+                Self::parse_notes(data.get(2..)?)
+            }
+            _ => None,
+        }
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        ([], 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNCHANGED: [u8; 3] = [0x7F; 3];
+
+    /// A MIDI tuning message, framed by `F0` and `F7`, for any device
+    fn sysex(universal: u8, sub_id: u8, data: &[u8]) -> Vec<u8> {
+        let mut message = vec![0xF0, universal, 0x7F, MIDI_TUNING, sub_id];
+        message.extend_from_slice(data);
+        message.push(0xF7);
+        message
+    }
+
+    /// Pitches of a bulk dump, every note half a semitone up, but the 5th, left unchanged
+    fn pitches() -> Vec<u8> {
+        (0..128u8)
+            .flat_map(|note| if note == 5 { UNCHANGED } else { [note, 0x40, 0] })
+            .collect()
+    }
+
+    fn changes(buffer: &[u8]) -> Option<Vec<(u8, f32)>> {
+        MtsMessage::from_buffer(buffer).map(|message| message.changes().to_vec())
+    }
+
+    #[test]
+    fn parses_pitches() {
+        assert_eq!(MtsPitch([60, 0, 0]).note(), Some(60.));
+        assert_eq!(MtsPitch([60, 0x40, 0]).note(), Some(60.5));
+        assert_eq!(MtsPitch([60, 0x7F, 0x7F]).note(), Some(60. + 16383. / 16384.));
+        assert_eq!(MtsPitch(UNCHANGED).note(), None);
+    }
+
+    #[test]
+    fn parses_bulk_dumps() {
+        // program number, name, pitches, then checksum
+        let mut data = vec![0; 1 + NAME_LEN];
+        data.extend(pitches());
+        data.push(0);
+
+        let changes = changes(&sysex(NON_REAL_TIME, BULK_DUMP, &data)).unwrap();
+
+        assert_eq!(changes.len(), 127);
+        assert!(changes.iter().all(|&(note, pitch)| note != 5 && pitch == note as f32 + 0.5));
+    }
+
+    #[test]
+    fn parses_bank_bulk_dumps() {
+        // bank and program numbers, name, then pitches
+        let mut data = vec![0; 2 + NAME_LEN];
+        data.extend(pitches());
+
+        let changes = changes(&sysex(NON_REAL_TIME, BANK_BULK_DUMP, &data)).unwrap();
+        assert_eq!(changes.len(), 127);
+    }
+
+    #[test]
+    fn rejects_truncated_bulk_dumps() {
+        let mut data = vec![0; 1 + NAME_LEN];
+        data.extend(&pitches()[..127 * 3]);
+
+        assert_eq!(changes(&sysex(NON_REAL_TIME, BULK_DUMP, &data)), None);
+        assert_eq!(changes(&sysex(NON_REAL_TIME, BULK_DUMP, &[0; NAME_LEN])), None);
+        assert_eq!(changes(&sysex(NON_REAL_TIME, BANK_BULK_DUMP, &data[..NAME_LEN])), None);
+    }
+
+    #[test]
+    fn parses_single_note_changes() {
+        // program number, count, then key and pitch of every change, the last one wins
+        let data = [0, 4, 60, 61, 0, 0, 64, 0x7F, 0x7F, 0x7F, 62, 1, 0, 0, 62, 3, 0x40, 0];
+
+        let changes = changes(&sysex(REAL_TIME, SINGLE_NOTE, &data)).unwrap();
+        assert_eq!(changes, [(60, 61.), (62, 3.5)]);
+    }
+
+    #[test]
+    fn parses_bank_single_note_changes() {
+        // bank and program numbers, count, then changes
+        let data = [0, 0, 1, 69, 69, 0x20, 0];
+
+        for universal in [REAL_TIME, NON_REAL_TIME] {
+            let changes = changes(&sysex(universal, BANK_SINGLE_NOTE, &data)).unwrap();
+            assert_eq!(changes, [(69, 69.25)]);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_single_note_changes() {
+        // fewer changes than counted
+        assert_eq!(changes(&sysex(REAL_TIME, SINGLE_NOTE, &[0, 2, 60, 60, 0, 0])), None);
+        assert_eq!(changes(&sysex(REAL_TIME, SINGLE_NOTE, &[0, 1, 60, 60])), None);
+        assert_eq!(changes(&sysex(REAL_TIME, SINGLE_NOTE, &[0])), None);
+        // too many changes
+        assert_eq!(changes(&sysex(REAL_TIME, SINGLE_NOTE, &[0, 128])), None);
+        // keys out of range
+        assert_eq!(changes(&sysex(REAL_TIME, SINGLE_NOTE, &[0, 1, 128, 60, 0, 0])), None);
+        // single note changes are only real time
+        assert_eq!(changes(&sysex(NON_REAL_TIME, SINGLE_NOTE, &[0, 1, 60, 60, 0, 0])), None);
+    }
+
+    #[test]
+    fn ignores_framing() {
+        let framed = sysex(REAL_TIME, SINGLE_NOTE, &[0, 1, 60, 60, 0, 0]);
+        let unframed = &framed[1..framed.len() - 1];
+
+        assert_eq!(changes(unframed), Some(vec![(60, 60.)]));
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        // general MIDI on
+        assert_eq!(changes(&[0xF0, NON_REAL_TIME, 0x7F, 0x09, 0x01, 0xF7]), None);
+        // a tuning dump request
+        assert_eq!(changes(&sysex(NON_REAL_TIME, 0x00, &[0])), None);
+        assert_eq!(changes(&[0xF0, REAL_TIME, 0x7F, 0xF7]), None);
+        assert_eq!(changes(&[]), None);
+    }
+}
@@ -0,0 +1,498 @@
+use arrayvec::ArrayVec;
+use nih_plug::prelude::{Enum, NoteEvent};
+use std::simd::{f32x2, SimdFloat};
+
+/// How much a voice's tracked level decays every sample
+const LEVEL_DECAY: f32 = 0.9995;
+
+/// What to do with a new note when every voice is already playing
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoiceStealMode {
+    #[name = "Steal Oldest"]
+    Oldest,
+    #[name = "Steal Quietest"]
+    Quietest,
+    #[name = "Steal Lowest"]
+    Lowest,
+    #[name = "Steal Highest"]
+    Highest,
+    #[name = "No Stealing"]
+    NoSteal,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayMode {
+    #[name = "Poly"]
+    Poly,
+    /// one voice at a time, retriggered on every note
+    #[name = "Mono"]
+    Mono,
+    /// one voice at a time, that only changes pitch on overlapping notes
+    #[name = "Legato"]
+    Legato,
+}
+
+#[derive(PartialEq, Eq)]
+enum VoiceState {
+    /// the note is still held down
+    Held,
+    /// the note has been released, but a pedal is holding it
+    Sustained,
+    /// the note has been released, but the voice is still decaying
+    Releasing,
+}
+
+/// Per-voice (polyphonic/MPE) expression
+#[derive(Default, Clone, Copy)]
+pub struct VoiceExpression {
+    /// in semitones
+    pub tuning: f32,
+    pub pressure: f32,
+    pub brightness: f32,
+}
+
+/// Identifies a voice the way the host does
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct VoiceKey {
+    pub channel: u8,
+    pub note: u8,
+    pub voice_id: i32,
+}
+
+impl VoiceKey {
+    /// Hosts that don't provide voice IDs get one made up from the channel and note
+    pub fn new(channel: u8, note: u8, voice_id: Option<i32>) -> Self {
+        Self {
+            channel,
+            note,
+            voice_id: voice_id.unwrap_or(note as i32 | (channel as i32) << 16),
+        }
+    }
+
+    /// Whether this is the voice the host refers to with `voice_id`, or, if
+    /// it doesn't provide one, with `channel` and `note`
+    pub fn matches(&self, voice_id: Option<i32>, channel: u8, note: u8) -> bool {
+        match voice_id {
+            Some(voice_id) => self.voice_id == voice_id,
+            None => (self.channel, self.note) == (channel, note),
+        }
+    }
+
+    /// Event telling the host this voice has ended
+    pub fn terminated<S>(self, timing: u32) -> NoteEvent<S> {
+        NoteEvent::VoiceTerminated {
+            timing,
+            voice_id: Some(self.voice_id),
+            channel: self.channel,
+            note: self.note,
+        }
+    }
+}
+
+struct Voice {
+    key: VoiceKey,
+    state: VoiceState,
+    /// whether this voice was held when the sostenuto pedal was pressed
+    sostenuto: bool,
+    expression: VoiceExpression,
+    /// when this voice was started, greater is younger
+    age: u64,
+    /// decaying peak level of the voice's output
+    level: f32,
+}
+
+/// Where a new voice ended up, the new voice is always the last one
+pub enum Allocation {
+    /// A free slot was available
+    Free,
+    /// The voice at this index, with this key, was removed (swap-removed) to make room
+    Stolen(usize, VoiceKey),
+}
+
+/// Keeps track of the notes played by every voice, in the same
+/// order as the voices of the processor they're mirroring
+#[derive(Default)]
+pub struct VoiceHandler<const VOICES: usize> {
+    voices: ArrayVec<Voice, VOICES>,
+    clock: u64,
+    sustain_pedal: bool,
+    sostenuto_pedal: bool,
+}
+
+impl<const VOICES: usize> VoiceHandler<VOICES> {
+    pub fn len(&self) -> usize {
+        self.voices.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.voices.clear();
+        self.sustain_pedal = false;
+        self.sostenuto_pedal = false;
+    }
+
+    /// Allocates a voice for `key`, stealing one according to `steal_mode` if
+    /// they are all taken. Returns `None` if the note has to be dropped.
+    pub fn note_on(&mut self, key: VoiceKey, steal_mode: VoiceStealMode) -> Option<Allocation> {
+        let allocation = if self.voices.is_full() {
+            let victim = self.victim(steal_mode)?;
+            Allocation::Stolen(victim, self.remove(victim))
+        } else {
+            Allocation::Free
+        };
+
+        self.clock += 1;
+        self.voices.push(Voice {
+            key,
+            state: VoiceState::Held,
+            sostenuto: false,
+            expression: Default::default(),
+            age: self.clock,
+            level: 0.,
+        });
+
+        Some(allocation)
+    }
+
+    /// Releases the note of the voice the host refers to, returning its index if
+    /// it has entered its release phase, i. e. if no pedal is holding it.
+    /// If the same note is held by several voices, the oldest one is released first.
+    pub fn note_off(&mut self, voice_id: Option<i32>, channel: u8, note: u8) -> Option<usize> {
+        let (voice_idx, _) = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter(|(_, voice)| {
+                voice.state == VoiceState::Held && voice.key.matches(voice_id, channel, note)
+            })
+            .min_by_key(|(_, voice)| voice.age)?;
+
+        let voice = &mut self.voices[voice_idx];
+
+        if self.sustain_pedal || voice.sostenuto {
+            voice.state = VoiceState::Sustained;
+            None
+        } else {
+            voice.state = VoiceState::Releasing;
+            Some(voice_idx)
+        }
+    }
+
+    /// Moves the voice at `voice_idx` into its release phase, whatever the pedals say
+    pub fn release(&mut self, voice_idx: usize) {
+        self.voices[voice_idx].state = VoiceState::Releasing;
+    }
+
+    /// Releases the voice playing `key`'s note that is only held by a pedal, if
+    /// any, for that note to be played again. Returns the released voice's index.
+    pub fn release_sustained(&mut self, key: VoiceKey) -> Option<usize> {
+        let voice_idx = self.voices.iter().position(|voice| {
+            voice.state == VoiceState::Sustained
+                && (voice.key.note, voice.key.channel) == (key.note, key.channel)
+        })?;
+
+        self.release(voice_idx);
+        Some(voice_idx)
+    }
+
+    /// Returns the indices of the voices released by lifting the pedals
+    fn release_unsustained(&mut self) -> ArrayVec<usize, VOICES> {
+        let sustain_pedal = self.sustain_pedal;
+
+        self.voices
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, voice)| {
+                voice.state == VoiceState::Sustained && !sustain_pedal && !voice.sostenuto
+            })
+            .map(|(i, voice)| {
+                voice.state = VoiceState::Releasing;
+                i
+            })
+            .collect()
+    }
+
+    /// Returns the indices of the voices that have entered their release phase
+    pub fn set_sustain_pedal(&mut self, down: bool) -> ArrayVec<usize, VOICES> {
+        self.sustain_pedal = down;
+        self.release_unsustained()
+    }
+
+    /// Pressing the sostenuto pedal holds the notes currently held down, and only those.
+    /// Returns the indices of the voices that have entered their release phase.
+    pub fn set_sostenuto_pedal(&mut self, down: bool) -> ArrayVec<usize, VOICES> {
+        if down == self.sostenuto_pedal {
+            return ArrayVec::new();
+        }
+
+        self.sostenuto_pedal = down;
+
+        for voice in self.voices.iter_mut() {
+            voice.sostenuto = down && voice.state == VoiceState::Held;
+        }
+
+        self.release_unsustained()
+    }
+
+    /// Index of the youngest voice whose note is still held, down or by a pedal, if any.
+    /// In the mono play modes, that's the voice new notes take over.
+    pub fn held(&self) -> Option<usize> {
+        self.voices
+            .iter()
+            .enumerate()
+            .filter(|(_, voice)| voice.state != VoiceState::Releasing)
+            .max_by_key(|(_, voice)| voice.age)
+            .map(|(i, _)| i)
+    }
+
+    /// Makes the voice at `voice_idx` play `key`, held down, instead, returning the key it had before
+    pub fn rekey(&mut self, voice_idx: usize, key: VoiceKey) -> VoiceKey {
+        let voice = &mut self.voices[voice_idx];
+        voice.state = VoiceState::Held;
+        std::mem::replace(&mut voice.key, key)
+    }
+
+    /// Index of the youngest voice the host refers to, see `VoiceKey::matches`, if any
+    pub fn find(&self, voice_id: Option<i32>, channel: u8, note: u8) -> Option<usize> {
+        self.voices
+            .iter()
+            .enumerate()
+            .filter(|(_, voice)| voice.key.matches(voice_id, channel, note))
+            .max_by_key(|(_, voice)| voice.age)
+            .map(|(i, _)| i)
+    }
+
+    /// Index of the voice with the given voice ID, if any
+    pub fn find_id(&self, voice_id: i32) -> Option<usize> {
+        self.voices
+            .iter()
+            .position(|voice| voice.key.voice_id == voice_id)
+    }
+
+    pub fn channel(&self, voice_idx: usize) -> u8 {
+        self.voices[voice_idx].key.channel
+    }
+
+    pub fn note(&self, voice_idx: usize) -> u8 {
+        self.voices[voice_idx].key.note
+    }
+
+    pub fn expression(&self, voice_idx: usize) -> &VoiceExpression {
+        &self.voices[voice_idx].expression
+    }
+
+    pub fn expression_mut(&mut self, voice_idx: usize) -> &mut VoiceExpression {
+        &mut self.voices[voice_idx].expression
+    }
+
+    pub fn is_releasing(&self, voice_idx: usize) -> bool {
+        self.voices[voice_idx].state == VoiceState::Releasing
+    }
+
+    /// Frees the voice at `voice_idx`, the last voice takes its place
+    pub fn remove(&mut self, voice_idx: usize) -> VoiceKey {
+        self.voices.swap_remove(voice_idx).key
+    }
+
+    /// Feed the last block rendered by the voice at `voice_idx` into its level meter
+    #[inline]
+    pub fn track_level(&mut self, voice_idx: usize, block: impl IntoIterator<Item = f32x2>) {
+        let voice = &mut self.voices[voice_idx];
+        voice.level = block.into_iter().fold(voice.level, |level, sample| {
+            sample.abs().reduce_max().max(level * LEVEL_DECAY)
+        });
+    }
+
+    fn victim(&self, steal_mode: VoiceStealMode) -> Option<usize> {
+        let voices = self.voices.iter().enumerate();
+
+        // voices that are already fading out go first, quietest first
+        let releasing = voices
+            .clone()
+            .filter(|(_, voice)| voice.state == VoiceState::Releasing)
+            .min_by(|(_, v1), (_, v2)| v1.level.total_cmp(&v2.level));
+
+        if let Some((i, _)) = releasing {
+            return Some(i);
+        }
+
+        match steal_mode {
+            VoiceStealMode::Oldest => voices.min_by_key(|(_, voice)| voice.age),
+            VoiceStealMode::Quietest => {
+                voices.min_by(|(_, v1), (_, v2)| v1.level.total_cmp(&v2.level))
+            }
+            VoiceStealMode::Lowest => voices.min_by_key(|(_, voice)| voice.key.note),
+            VoiceStealMode::Highest => voices.max_by_key(|(_, voice)| voice.key.note),
+            VoiceStealMode::NoSteal => None,
+        }
+        .map(|(i, _)| i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(note: u8) -> VoiceKey {
+        VoiceKey::new(0, note, None)
+    }
+
+    /// Handler with a voice for each of `notes`, started in that order, at the given `levels`
+    fn handler(notes: [u8; 4], levels: [f32; 4]) -> VoiceHandler<4> {
+        let mut handler = VoiceHandler::default();
+
+        for (voice_idx, (note, level)) in notes.into_iter().zip(levels).enumerate() {
+            let allocation = handler.note_on(key(note), VoiceStealMode::Oldest);
+            assert!(matches!(allocation, Some(Allocation::Free)));
+            handler.track_level(voice_idx, [f32x2::splat(level)]);
+        }
+
+        handler
+    }
+
+    /// Index and note of the voice stolen for a new note, `None` if it was dropped
+    fn steal(handler: &mut VoiceHandler<4>, steal_mode: VoiceStealMode) -> Option<(usize, u8)> {
+        match handler.note_on(key(100), steal_mode)? {
+            Allocation::Stolen(voice_idx, stolen) => Some((voice_idx, stolen.note)),
+            Allocation::Free => panic!("a voice was free"),
+        }
+    }
+
+    const NOTES: [u8; 4] = [62, 60, 63, 61];
+    const LEVELS: [f32; 4] = [0.5, 0.8, 0.1, 0.3];
+
+    #[test]
+    fn steals_according_to_steal_mode() {
+        let cases = [
+            (VoiceStealMode::Oldest, Some((0, 62))),
+            (VoiceStealMode::Quietest, Some((2, 63))),
+            (VoiceStealMode::Lowest, Some((1, 60))),
+            (VoiceStealMode::Highest, Some((2, 63))),
+            (VoiceStealMode::NoSteal, None),
+        ];
+
+        for (steal_mode, expected) in cases {
+            let mut handler = handler(NOTES, LEVELS);
+            assert_eq!(steal(&mut handler, steal_mode), expected, "{steal_mode:?}");
+
+            let new_voices = if expected.is_some() { 1 } else { 0 };
+            assert_eq!(handler.len(), 4);
+            assert_eq!((0..4).filter(|&i| handler.note(i) == 100).count(), new_voices);
+        }
+    }
+
+    #[test]
+    fn steals_releasing_voices_first() {
+        let steal_modes = [VoiceStealMode::Oldest, VoiceStealMode::Highest, VoiceStealMode::NoSteal];
+
+        for steal_mode in steal_modes {
+            let mut handler = handler(NOTES, LEVELS);
+
+            // the quietest of them
+            assert_eq!(handler.note_off(None, 0, 60), Some(1));
+            assert_eq!(handler.note_off(None, 0, 61), Some(3));
+
+            assert_eq!(steal(&mut handler, steal_mode), Some((3, 61)), "{steal_mode:?}");
+        }
+    }
+
+    #[test]
+    fn releases_oldest_duplicate_first() {
+        let mut handler = VoiceHandler::<4>::default();
+
+        for key in [key(60), VoiceKey::new(1, 60, None), key(60), VoiceKey::new(0, 60, Some(7))] {
+            handler.note_on(key, VoiceStealMode::Oldest);
+        }
+
+        // by voice ID, whatever the age
+        assert_eq!(handler.note_off(Some(7), 0, 60), Some(3));
+        // by channel and note, oldest first
+        assert_eq!(handler.note_off(None, 0, 60), Some(0));
+        assert_eq!(handler.note_off(None, 0, 60), Some(2));
+        assert_eq!(handler.note_off(None, 0, 60), None);
+
+        assert!(!handler.is_releasing(1));
+        assert_eq!(handler.note_off(None, 1, 60), Some(1));
+    }
+
+    #[test]
+    fn sustain_holds_released_notes() {
+        let mut handler = handler(NOTES, LEVELS);
+
+        assert!(handler.set_sustain_pedal(true).is_empty());
+        assert_eq!(handler.note_off(None, 0, 60), None);
+        assert_eq!(handler.note_off(None, 0, 63), None);
+        assert!(!handler.is_releasing(1) && !handler.is_releasing(2));
+
+        assert_eq!(handler.set_sustain_pedal(false).as_slice(), &[1, 2]);
+        assert!(handler.is_releasing(1) && handler.is_releasing(2));
+        assert!(!handler.is_releasing(0) && !handler.is_releasing(3));
+    }
+
+    #[test]
+    fn sostenuto_only_holds_notes_held_when_pressed() {
+        let mut handler = VoiceHandler::<4>::default();
+
+        handler.note_on(key(60), VoiceStealMode::Oldest);
+        handler.note_on(key(62), VoiceStealMode::Oldest);
+        handler.note_off(None, 0, 62);
+
+        assert!(handler.set_sostenuto_pedal(true).is_empty());
+        handler.note_on(key(64), VoiceStealMode::Oldest);
+
+        assert_eq!(handler.note_off(None, 0, 60), None);
+        assert_eq!(handler.note_off(None, 0, 64), Some(2));
+
+        // pressing it again while down changes nothing
+        assert!(handler.set_sostenuto_pedal(true).is_empty());
+        assert_eq!(handler.set_sostenuto_pedal(false).as_slice(), &[0]);
+    }
+
+    #[test]
+    fn sustain_and_sostenuto_hold_notes_together() {
+        let mut handler = VoiceHandler::<4>::default();
+
+        handler.note_on(key(60), VoiceStealMode::Oldest);
+        handler.set_sostenuto_pedal(true);
+        handler.set_sustain_pedal(true);
+        handler.note_on(key(64), VoiceStealMode::Oldest);
+
+        assert_eq!(handler.note_off(None, 0, 60), None);
+        assert_eq!(handler.note_off(None, 0, 64), None);
+
+        // the sostenuto pedal still holds the first note
+        assert_eq!(handler.set_sustain_pedal(false).as_slice(), &[1]);
+        assert_eq!(handler.set_sostenuto_pedal(false).as_slice(), &[0]);
+    }
+
+    #[test]
+    fn retriggers_release_sustained_voices() {
+        let mut handler = VoiceHandler::<4>::default();
+
+        handler.set_sustain_pedal(true);
+        handler.note_on(key(60), VoiceStealMode::Oldest);
+        handler.note_on(key(62), VoiceStealMode::Oldest);
+        handler.note_off(None, 0, 60);
+
+        // notes still held down aren't released
+        assert_eq!(handler.release_sustained(key(62)), None);
+        // nor are those of other channels
+        assert_eq!(handler.release_sustained(VoiceKey::new(1, 60, None)), None);
+
+        assert_eq!(handler.release_sustained(key(60)), Some(0));
+        assert!(handler.is_releasing(0));
+        assert_eq!(handler.release_sustained(key(60)), None);
+    }
+
+    #[test]
+    fn sustained_voices_count_as_held() {
+        let mut handler = VoiceHandler::<4>::default();
+
+        handler.set_sustain_pedal(true);
+        handler.note_on(key(60), VoiceStealMode::Oldest);
+        handler.note_off(None, 0, 60);
+        assert_eq!(handler.held(), Some(0));
+
+        handler.set_sustain_pedal(false);
+        assert_eq!(handler.held(), None);
+    }
+}